@@ -3,7 +3,7 @@ use std::{
     sync::{Arc, OnceLock, Weak},
 };
 
-use laylay_common::{Info, Message, SecretKey, Version};
+use laylay_common::{fingerprint, Info, Message, Version};
 use tokio::{
     net::TcpStream,
     runtime::Runtime,
@@ -17,7 +17,12 @@ use winit::{
 };
 
 use crate::{
-    context::{counter::FrameCounter, render::RenderContext, xr::XrContext},
+    context::{
+        counter::FrameCounter,
+        profiles::{Profile, Profiles},
+        render::RenderContext,
+        xr::XrContext,
+    },
     errors::ClientError,
     logger::Logger,
     math::matrix,
@@ -34,17 +39,135 @@ use crate::{
 
 //     file.write_all(msg.as_bytes()).unwrap();
 // }
+/// Where the key and other private files live, on Android the shared storage is readable by other apps.
+#[cfg(target_os = "android")]
+fn data_folder() -> Result<PathBuf, ClientError> {
+    use jni::objects::{JObject, JString};
+
+    let ret: Result<String, jni::errors::Error> = (|| {
+        let ctx = ndk_context::android_context();
+        let vm = unsafe { jni::JavaVM::from_raw(ctx.vm().cast()) }?;
+        let mut env = vm.attach_current_thread()?;
+        let activity = unsafe { JObject::from_raw(ctx.context().cast()) };
+
+        let dir = env
+            .call_method(&activity, "getFilesDir", "()Ljava/io/File;", &[])?
+            .l()?;
+        let path = env
+            .call_method(&dir, "getAbsolutePath", "()Ljava/lang/String;", &[])?
+            .l()?;
+
+        Ok(env.get_string(&JString::from(path))?.into())
+    })();
+
+    ret.map(PathBuf::from)
+        .map_err(|e| ClientError::internal(&format!("files dir: {e}")))
+}
+
+#[cfg(not(target_os = "android"))]
+fn data_folder() -> Result<PathBuf, ClientError> {
+    Ok(PathBuf::from("data/"))
+}
+
+/// Where earlier versions kept the key, shared storage that other apps can read.
+#[cfg(target_os = "android")]
+const LEGACY_DATA_FOLDER: &str = "/sdcard/Documents/laylay/";
+
+/// Moves the key and pinned servers of earlier versions into `folder` once,
+/// so existing installs keep their identity, and removes the readable copies.
+#[cfg(target_os = "android")]
+fn move_legacy_data(folder: &std::path::Path) -> Result<(), ClientError> {
+    let legacy = std::path::Path::new(LEGACY_DATA_FOLDER);
+
+    if !legacy.join("prikey.bin").exists() || folder.join("prikey.bin").exists() {
+        return Ok(());
+    }
+
+    // The key goes last, once it is in place the move counts as done.
+    let files = ["known_servers.txt", "prikey.bin"];
+
+    for name in files {
+        if legacy.join(name).exists() {
+            std::fs::copy(legacy.join(name), folder.join(name))?;
+        }
+    }
+
+    for name in files {
+        if legacy.join(name).exists() {
+            std::fs::remove_file(legacy.join(name))?;
+        }
+    }
+
+    tracing::info!(
+        "moved key from {LEGACY_DATA_FOLDER} to {}",
+        folder.display()
+    );
+
+    Ok(())
+}
+
+/// The profiles and the one to start with, `LAYLAY_PROFILE` overrides the last selected.
+fn open_profile() -> Result<(Profiles, Profile), ClientError> {
+    let folder = data_folder()?;
+
+    if !folder.exists() {
+        std::fs::create_dir_all(&folder)?;
+    }
+
+    #[cfg(target_os = "android")]
+    move_legacy_data(&folder)?;
+
+    let passphrase = std::env::var("LAYLAY_KEY_PASSPHRASE").ok();
+    let profiles = Profiles::new(folder, passphrase);
+    let name = match std::env::var("LAYLAY_PROFILE") {
+        Ok(name) => name,
+        Err(_) => profiles.selected()?,
+    };
+    let profile = profiles.open(&name)?;
+    profiles.select(&name)?;
+
+    Ok((profiles, profile))
+}
+
 pub static CTX: OnceLock<Arc<Context<'static>>> = OnceLock::new();
 
 pub struct Context<'a> {
     me: Weak<Context<'a>>,
-    prikey: SecretKey,
+    profiles: Profiles,
+    profile: RwLock<Profile>,
     pub runtime: Arc<Runtime>,
     pub state: Mutex<RenderContext<'a>>,
     xr: Option<XrContext>,
     scene: RwLock<Option<ScenePtr>>,
 }
 
+/// Shows whose key is in use, so it can be compared with what the others see.
+fn title(profile: &Profile) -> String {
+    let pubkey = profile.prikey().public_key().to_sec1_bytes();
+
+    format!("laylay - {} - {}", profile.name, fingerprint(&pubkey))
+}
+
+impl Context<'_> {
+    pub fn profiles(&self) -> &Profiles {
+        &self.profiles
+    }
+
+    pub async fn profile_name(&self) -> String {
+        self.profile.read().await.name.clone()
+    }
+
+    /// Changes the identity used for the next connection, it is remembered for the next start.
+    pub async fn switch_profile(&self, name: &str) -> Result<(), ClientError> {
+        let profile = self.profiles.open(name)?;
+        self.profiles.select(name)?;
+        self.state.lock().await.window.set_title(&title(&profile));
+        *self.profile.write().await = profile;
+
+        Ok(())
+    }
+}
+
 pub struct App {
     counter: FrameCounter,
     runtime: Arc<Runtime>,
@@ -66,6 +189,16 @@ impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
         tracing::info!("resumed application");
 
+        // A wrong passphrase ends up here, there is nothing to run without a key.
+        let (profiles, profile) = match open_profile() {
+            Ok(ret) => ret,
+            Err(e) => {
+                tracing::error!("{e}");
+                event_loop.exit();
+                return;
+            }
+        };
+
         let xr = XrContext::new();
 
         let xr = match xr {
@@ -89,21 +222,13 @@ impl ApplicationHandler for App {
         #[cfg(target_os = "macos")]
         state.window.request_redraw();
 
-        let folder = if cfg!(target_os = "android") {
-            "/sdcard/Documents/laylay/"
-        } else {
-            "data/"
-        };
-        let folder = PathBuf::from(folder);
-
-        if !folder.exists() {
-            std::fs::create_dir_all(&folder).unwrap();
-        }
+        tracing::info!("using profile {}", title(&profile));
+        state.window.set_title(&title(&profile));
 
-        let prikey = laylay_common::get_private_key(folder).unwrap();
         let ctx = Arc::new_cyclic(|me| Context {
             me: me.clone(),
-            prikey: prikey.clone(),
+            profiles,
+            profile: RwLock::new(profile),
             runtime: self.runtime.clone(),
             xr,
             state: Mutex::new(state),
@@ -0,0 +1,98 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use laylay_common::{fingerprint, Bytes};
+
+use crate::errors::ClientError;
+
+/// Server keys pinned on first use, stored as `address hexkey` lines in
+/// `known_servers.txt` next to the private key.
+pub struct KnownServers {
+    filename: PathBuf,
+    servers: BTreeMap<String, Bytes>,
+    offered: BTreeMap<String, Bytes>,
+}
+
+impl KnownServers {
+    pub fn load(folder: PathBuf) -> Result<Self, ClientError> {
+        let filename = folder.join("known_servers.txt");
+        let mut servers = BTreeMap::new();
+
+        if filename.exists() {
+            let data = std::fs::read_to_string(&filename)?;
+
+            for line in data.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let (addr, key) = line.split_once(' ').ok_or_else(|| {
+                    ClientError::internal(&format!("malformed known server entry: {line}"))
+                })?;
+                let key = hex::decode(key.trim()).map_err(|e| {
+                    ClientError::internal(&format!("malformed key for {addr}: {e}"))
+                })?;
+
+                servers.insert(addr.to_string(), Bytes::from(key));
+            }
+        }
+
+        Ok(Self {
+            filename,
+            servers,
+            offered: BTreeMap::new(),
+        })
+    }
+
+    /// Returns `true` when the key is already pinned for this address and
+    /// `false` when the address was never seen. A different key than the
+    /// pinned one is an error, the offered key is remembered and can be
+    /// looked up with [`KnownServers::offered`].
+    pub fn check(&mut self, addr: &str, pubkey: &Bytes) -> Result<bool, ClientError> {
+        match self.servers.get(addr) {
+            Some(pinned) if pinned == pubkey => Ok(true),
+            Some(_) => {
+                self.offered.insert(addr.to_string(), pubkey.clone());
+                let msg = format!(
+                    "{addr} now presents {} ({})",
+                    fingerprint(pubkey),
+                    hex::encode(pubkey)
+                );
+                Err(ClientError::server_key_changed(&msg, pubkey.clone()))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The key a server presented when it did not match the pinned one.
+    pub fn offered(&self, addr: &str) -> Option<&Bytes> {
+        self.offered.get(addr)
+    }
+
+    /// Pins the key the address offered last, `false` when it offered none.
+    pub fn accept_offered(&mut self, addr: &str) -> Result<bool, ClientError> {
+        match self.offered.get(addr).cloned() {
+            Some(pubkey) => self.accept(addr, pubkey).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Pins `pubkey` for the address, replacing whatever was trusted before.
+    pub fn accept(&mut self, addr: &str, pubkey: Bytes) -> Result<(), ClientError> {
+        self.offered.remove(addr);
+        self.servers.insert(addr.to_string(), pubkey);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), ClientError> {
+        let data: String = self
+            .servers
+            .iter()
+            .map(|(addr, key)| format!("{addr} {}\n", hex::encode(key)))
+            .collect();
+
+        std::fs::write(&self.filename, data)?;
+
+        Ok(())
+    }
+}
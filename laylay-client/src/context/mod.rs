@@ -1,4 +1,6 @@
 pub mod counter;
+pub mod known_servers;
 pub mod network;
+pub mod profiles;
 pub mod render;
 pub mod xr;
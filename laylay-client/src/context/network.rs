@@ -1,62 +1,602 @@
-use laylay_common::{Info, Message, SecretKey, Version};
-use tokio::{net::TcpStream, sync::mpsc};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use laylay_common::{
+    fingerprint, run_clock_sync, run_pinger, Bytes, Capabilities, Channel, Clock, ClockConfig,
+    CommonError, CompressionConfig, CompressionStats, Connection, ConnectionReader,
+    ConnectionWriter, DatagramOpener, DatagramSealer, FrameCodec, HeartbeatConfig, Inbox, Info,
+    Liveness, Message, Negotiated, Outbox, Policy, RekeyConfig, Request, Response, Rpc, RpcError,
+    SecretKey, WebSocket, DEFAULT_RPC_TIMEOUT, MAX_DATAGRAM_SIZE,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UdpSocket},
+};
 
 use crate::{errors::ClientError, logger::Logger};
 
-pub struct Network {}
+use super::{known_servers::KnownServers, profiles::Profile};
 
-impl Network {
-    pub async fn connect(prikey: &SecretKey) -> Result<Self, ClientError> {
-        let addr = if cfg!(target_os = "android") {
-            "192.168.1.9"
+/// Messages buffered per channel while the server is unreachable before senders have to wait.
+const OUTBOX_SIZE: usize = 256;
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// TCP or WebSocket, whatever the server address asks for.
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+type Halves = (
+    ConnectionWriter<Box<dyn Transport>>,
+    ConnectionReader<Box<dyn Transport>>,
+);
+
+/// How the connection to the server is kept alive, rekeyed, compressed and
+/// how often the clock is synchronised.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetworkConfig {
+    pub heartbeat: HeartbeatConfig,
+    pub rekey: RekeyConfig,
+    pub compression: CompressionConfig,
+    pub clock: ClockConfig,
+}
+
+pub struct Network {
+    known_servers: Arc<Mutex<KnownServers>>,
+    server: String,
+    negotiated: Arc<Mutex<Negotiated>>,
+    rpc: Arc<Rpc>,
+    liveness: Arc<Liveness>,
+    compression: Arc<CompressionStats>,
+    clock: Arc<Clock>,
+    datagram: Arc<Mutex<Option<Arc<Datagram>>>>,
+    txch: Outbox,
+}
+
+/// Realtime path to the server over UDP, offered with [`Message::Datagram`].
+struct Datagram {
+    socket: UdpSocket,
+    sealer: Mutex<DatagramSealer>,
+    opener: Mutex<DatagramOpener>,
+}
+
+impl Datagram {
+    async fn open(
+        addr: SocketAddr,
+        sealer: DatagramSealer,
+        opener: DatagramOpener,
+    ) -> Result<Self, ClientError> {
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
         } else {
-            "127.0.0.1"
+            "[::]:0"
         };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+
+        Ok(Self {
+            socket,
+            sealer: Mutex::new(sealer),
+            opener: Mutex::new(opener),
+        })
+    }
+
+    /// Sends `msg` unless it is too big for a datagram, then it is handed back.
+    async fn send(&self, msg: Message) -> Result<Option<Message>, ClientError> {
+        let packet = self.sealer.lock().unwrap().seal(&msg)?;
+
+        if packet.len() > MAX_DATAGRAM_SIZE {
+            return Ok(Some(msg));
+        }
 
-        let public = prikey.public_key().to_sec1_bytes();
-        let mut stream = TcpStream::connect((addr, 33033)).await?;
-        let greeting = Message::Greeting {
-            pubkey: public.into(),
-            version: Version::get(),
-            info: Info::new()?,
+        self.socket.send(&packet).await?;
+
+        Ok(None)
+    }
+
+    async fn recv(&self) -> Result<Message, ClientError> {
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let len = self.socket.recv(&mut buffer).await?;
+
+            match self.opener.lock().unwrap().open(&buffer[..len]) {
+                Ok(msg) => return Ok(msg),
+                Err(e) => tracing::debug!("dropped datagram: {e}"),
+            }
+        }
+    }
+}
+
+/// Everything needed to open the connection again after it dropped.
+struct Link {
+    prikey: SecretKey,
+    /// Shared with [`Network::accept_server_key`].
+    known_servers: Arc<Mutex<KnownServers>>,
+    config: NetworkConfig,
+    /// Address of the server as `host:port`, or `ws://host:port/path` for WebSocket.
+    server: String,
+    /// Issued by the server, continues the session on the next connection.
+    token: Option<Bytes>,
+    negotiated: Arc<Mutex<Negotiated>>,
+    rpc: Arc<Rpc>,
+    liveness: Arc<Liveness>,
+    compression: Arc<CompressionStats>,
+    clock: Arc<Clock>,
+    datagram: Arc<Mutex<Option<Arc<Datagram>>>>,
+    txch: Outbox,
+}
+
+impl Link {
+    async fn open(&mut self) -> Result<Halves, ClientError> {
+        let server = self.server.clone();
+        let codec = FrameCodec::default();
+        let (stream, addr): (Box<dyn Transport>, _) = if server.starts_with("ws://") {
+            let ws = WebSocket::connect(&server, codec.max_frame_size()).await?;
+            let addr = ws.get_ref().peer_addr()?;
+            (Box::new(ws), addr)
+        } else {
+            let stream = TcpStream::connect(&server).await?;
+            let addr = stream.peer_addr()?;
+            (Box::new(stream), addr)
         };
-        laylay_common::write_greeting(&mut stream, &greeting).await?;
+        let mut conn = Connection::connect(
+            stream,
+            &self.prikey,
+            Info::new()?,
+            codec,
+            &Policy::default(),
+        )
+        .await?;
+        conn.set_rekey(self.config.rekey);
+        conn.set_compression(self.config.compression, self.compression.clone());
+        let pubkey = conn.peer().pubkey.clone();
+        let negotiated = conn.peer().negotiated;
 
-        let ret = laylay_common::read_greeting(&mut stream).await?;
-        if let Message::Greeting {
-            pubkey,
-            version: _,
-            info: _,
-        } = ret
         {
-            let shared = laylay_common::shared_secret(pubkey, &prikey);
-            let (mut rx, mut tx) = stream.into_split();
-            let (txch, mut rxch) = mpsc::channel::<Message>(10);
+            let mut known_servers = self.known_servers.lock().unwrap();
+            if !known_servers.check(&server, &pubkey)? {
+                tracing::info!("pinning key {} of {server}", fingerprint(&pubkey));
+                known_servers.accept(&server, pubkey)?;
+            }
+        }
+
+        if negotiated.capabilities.contains(Capabilities::RESUME) {
+            let token = self.token.take();
+            conn.send(&Message::Resume { token }).await?;
+
+            match conn.recv().await? {
+                Message::Session { token, resumed } => {
+                    if resumed {
+                        tracing::info!("resumed session with {server}");
+                    }
+                    self.token = Some(token);
+                }
+                Message::Rejected { reason } => return Err(ClientError::rejected(&reason)),
+                _ => return Err(CommonError::protocol("expected session").into()),
+            }
+        } else {
+            self.token = None;
+        }
+
+        let datagram = if negotiated.capabilities.contains(Capabilities::DATAGRAM) {
+            match conn.recv().await? {
+                Message::Datagram { port, id } => {
+                    let (sealer, opener) = conn
+                        .datagrams(id)
+                        .ok_or_else(|| ClientError::internal("datagram keys already taken"))?;
+                    let addr = SocketAddr::new(addr.ip(), port);
+
+                    // Realtime state still works over the stream, so this is no reason to give up.
+                    match Datagram::open(addr, sealer, opener).await {
+                        Ok(datagram) => Some(Arc::new(datagram)),
+                        Err(e) => {
+                            tracing::warn!("no datagrams to {addr}: {e}");
+                            None
+                        }
+                    }
+                }
+                _ => return Err(CommonError::protocol("expected datagram").into()),
+            }
+        } else {
+            None
+        };
+
+        *self.negotiated.lock().unwrap() = negotiated;
+        *self.datagram.lock().unwrap() = datagram;
+
+        Ok(conn.split())
+    }
+
+    /// Tries again with exponential backoff until it works or the error is fatal.
+    /// A changed server key keeps it trying until [`Network::accept_server_key`].
+    async fn reopen(&mut self) -> Option<Halves> {
+        let mut backoff = MIN_BACKOFF;
+
+        loop {
+            tracing::info!("reconnecting to {} in {backoff:?}", self.server);
+            tokio::time::sleep(backoff).await;
+
+            match self.open().await {
+                Ok(halves) => return Some(halves),
+                Err(e) if e.is_fatal() => {
+                    tracing::error!("{e}");
+                    return None;
+                }
+                Err(e) => tracing::warn!("{e}"),
+            }
 
-            tracing::subscriber::set_global_default(Logger::new(txch))?;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Everything the server sends, over the connection and as datagrams alike.
+    async fn handle(&self, msg: Message) -> Result<(), ClientError> {
+        match msg {
+            Message::Response { id, result } => {
+                let known = self.rpc.complete(id, result).await;
+                if !known {
+                    tracing::warn!("response to unknown request {id}");
+                }
+            }
+            Message::Request { id, request: _ } => {
+                let result = Err(RpcError::Unsupported);
+                if let Err(e) = self.txch.send(Message::Response { id, result }).await {
+                    tracing::error!("{e}");
+                }
+            }
+            Message::Ping { sent } => {
+                if let Err(e) = self.txch.send(Message::Pong { sent }).await {
+                    tracing::error!("{e}");
+                }
+            }
+            Message::Pong { sent } => {
+                self.liveness.pong(sent);
+            }
+            Message::TimeResponse {
+                sent,
+                received,
+                replied,
+            } => {
+                self.clock.response(sent, received, replied);
+            }
+            Message::Rejected { reason } => {
+                return Err(ClientError::rejected(&reason));
+            }
+            Message::LobbyJoined { name, members } => {
+                tracing::info!("joined lobby {name} with {} members", members.len());
+            }
+            Message::LobbyRejected { name, reason } => {
+                tracing::warn!("could not join lobby {name}: {reason}");
+            }
+            Message::MemberJoined { lobby, pubkey } => {
+                tracing::info!("{} joined lobby {lobby}", fingerprint(&pubkey));
+            }
+            Message::MemberLeft { lobby, pubkey } => {
+                tracing::info!("{} left lobby {lobby}", fingerprint(&pubkey));
+            }
+            Message::ChatMessage {
+                lobby, from, text, ..
+            } => {
+                tracing::info!("[{lobby}] {}: {text}", fingerprint(&from));
+            }
+            Message::ChatRejected { lobby, reason } => {
+                tracing::warn!("chat in lobby {lobby} rejected: {reason}");
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Serves one connection until it drops. A message whose write was cut
+    /// short stays in `pending` and goes out first on the next connection.
+    /// Fails when the server closed the connection on purpose, like when the
+    /// same key logged in elsewhere, as reconnecting would only make it worse.
+    async fn run(
+        &self,
+        (mut tx, mut rx): Halves,
+        outbox: &mut Inbox,
+        pending: &mut Option<Message>,
+    ) -> Result<(), ClientError> {
+        let heartbeat = self.config.heartbeat;
+        let sync = self.config.clock;
+        let beating = self
+            .negotiated
+            .lock()
+            .unwrap()
+            .capabilities
+            .contains(Capabilities::HEARTBEAT);
+        let syncing = self
+            .negotiated
+            .lock()
+            .unwrap()
+            .capabilities
+            .contains(Capabilities::CLOCK);
+
+        let writer = async {
+            loop {
+                let msg = match pending.take() {
+                    Some(msg) => msg,
+                    None => match outbox.recv().await {
+                        Some(msg) => msg,
+                        None => return,
+                    },
+                };
+
+                let msg = pending.insert(msg);
+                if let Err(e) = tx.send(msg).await {
+                    tracing::error!("{e}");
+                    return;
+                }
+                *pending = None;
+            }
+        };
+
+        let reader = async {
+            loop {
+                let ret = if beating {
+                    match tokio::time::timeout(heartbeat.idle_timeout, rx.recv()).await {
+                        Ok(ret) => ret,
+                        Err(_) => {
+                            tracing::error!("server idle for {:?}", heartbeat.idle_timeout);
+                            return Ok(());
+                        }
+                    }
+                } else {
+                    rx.recv().await
+                };
 
-            let shared0 = shared.clone();
-            tokio::spawn(async move {
-                while let Some(msg) = rxch.recv().await {
-                    if let Err(e) = laylay_common::write(&shared0, &mut tx, &msg).await {
+                match ret {
+                    Ok(msg) => self.handle(msg).await?,
+                    Err(e) => {
                         tracing::error!("{e}");
+                        return Ok(());
                     }
                 }
-            });
+            }
+        };
+
+        // The first datagram tells the server where to send its own, later
+        // ones keep NAT mappings open. Losing the path only loses the shortcut.
+        let datagrams = async {
+            let Some(datagram) = self.datagram.lock().unwrap().clone() else {
+                return std::future::pending().await;
+            };
+
+            let pinger = async {
+                let mut ticker = tokio::time::interval(heartbeat.interval);
 
-            tokio::spawn(async move {
                 loop {
-                    let ret = laylay_common::read(&shared, &mut rx).await;
-                    match ret {
-                        Ok(msg) => {}
+                    ticker.tick().await;
+
+                    if let Err(e) = datagram.send(self.liveness.ping()).await {
+                        tracing::warn!("datagram: {e}");
+                    }
+                }
+            };
+
+            let reader = async {
+                loop {
+                    match datagram.recv().await {
+                        Ok(msg) => self.handle(msg).await?,
                         Err(e) => {
-                            tracing::error!("{e}");
+                            tracing::warn!("datagram: {e}");
+                            *self.datagram.lock().unwrap() = None;
+                            return Ok::<(), ClientError>(());
                         }
                     }
                 }
-            });
+            };
+
+            tokio::select! {
+                _ = pinger => {}
+                ret = reader => ret?,
+            }
+
+            std::future::pending().await
+        };
+
+        let ret = tokio::select! {
+            _ = writer => Ok(()),
+            ret = reader => ret,
+            _ = run_pinger(self.txch.clone(), &self.liveness, heartbeat.interval), if beating => Ok(()),
+            ret = datagrams => ret,
+            _ = run_clock_sync(self.txch.clone(), &self.clock, sync.interval), if syncing => Ok(()),
+        };
+
+        *self.datagram.lock().unwrap() = None;
+
+        ret
+    }
+}
+
+impl Network {
+    /// Connects to the server of the profile and keeps reconnecting in the background
+    /// when the connection drops. Messages sent in the meantime are delivered afterwards.
+    pub async fn connect(profile: &Profile, config: NetworkConfig) -> Result<Self, ClientError> {
+        let (txch, mut outbox) = Outbox::new(OUTBOX_SIZE);
+
+        let mut link = Link {
+            prikey: profile.prikey().clone(),
+            known_servers: Arc::new(Mutex::new(profile.known_servers()?)),
+            config,
+            server: profile.settings.server.clone(),
+            token: None,
+            negotiated: Arc::new(Mutex::new(Negotiated {
+                protocol: 0,
+                capabilities: Capabilities::empty(),
+            })),
+            rpc: Arc::new(Rpc::new(txch.clone())),
+            liveness: Arc::new(Liveness::new()),
+            compression: Arc::new(CompressionStats::default()),
+            clock: Arc::new(Clock::new()),
+            datagram: Arc::new(Mutex::new(None)),
+            txch: txch.clone(),
+        };
+        let mut halves = link.open().await?;
+
+        let network = Self {
+            known_servers: link.known_servers.clone(),
+            server: link.server.clone(),
+            negotiated: link.negotiated.clone(),
+            rpc: link.rpc.clone(),
+            liveness: link.liveness.clone(),
+            compression: link.compression.clone(),
+            clock: link.clock.clone(),
+            datagram: link.datagram.clone(),
+            txch: txch.clone(),
+        };
+
+        tracing::subscriber::set_global_default(Logger::new(txch, link.clock.clone()))?;
+
+        tokio::spawn(async move {
+            let mut pending = None;
+
+            loop {
+                let ret = link.run(halves, &mut outbox, &mut pending).await;
+                link.rpc.close().await;
+
+                if let Err(e) = ret {
+                    tracing::error!("{e}");
+                    break;
+                }
+
+                halves = match link.reopen().await {
+                    Some(halves) => halves,
+                    None => break,
+                };
+            }
+
+            // Nobody will deliver them anymore, but the logger must not fail
+            // on a closed channel as that would log again.
+            while outbox.recv().await.is_some() {}
+        });
+
+        Ok(network)
+    }
+
+    /// Trusts the key the server presented instead of the pinned one, the next
+    /// reconnect goes through with it. `false` when no other key was presented.
+    pub fn accept_server_key(&self) -> Result<bool, ClientError> {
+        let mut known_servers = self.known_servers.lock().unwrap();
+        let accepted = known_servers.accept_offered(&self.server)?;
+
+        if accepted {
+            tracing::info!("accepted the new key of {}", self.server);
         }
 
-        Ok(Self {})
+        Ok(accepted)
+    }
+
+    /// Protocol version and capabilities agreed with the server.
+    pub fn negotiated(&self) -> Negotiated {
+        *self.negotiated.lock().unwrap()
+    }
+
+    /// Round trip to the server, `None` before the first heartbeat was answered.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.liveness.rtt()
+    }
+
+    /// Estimate of the server clock, see [`Clock::server_time`].
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// What compressing outgoing messages saved over all connections so far.
+    pub fn compression_stats(&self) -> &CompressionStats {
+        &self.compression
+    }
+
+    /// Sends latest-value-wins state like poses, as a datagram when the server
+    /// offered UDP and on the realtime channel of the connection otherwise.
+    pub async fn send_realtime(&self, msg: Message) -> Result<(), ClientError> {
+        let datagram = self.datagram.lock().unwrap().clone();
+
+        let msg = match datagram {
+            Some(datagram) => match datagram.send(msg).await? {
+                Some(msg) => msg,
+                None => return Ok(()),
+            },
+            None => msg,
+        };
+
+        self.txch
+            .send_on(Channel::Realtime, msg)
+            .await
+            .map_err(|_| ClientError::internal("network task is gone"))
+    }
+
+    /// Asks the server and waits at most `timeout` for the answer.
+    pub async fn request(
+        &self,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<Response, ClientError> {
+        if !self.negotiated().capabilities.contains(Capabilities::RPC) {
+            return Err(RpcError::Unsupported.into());
+        }
+
+        Ok(self.rpc.call(request, timeout).await?)
+    }
+
+    pub async fn list_lobbies(&self) -> Result<Vec<String>, ClientError> {
+        match self
+            .request(Request::ListLobbies, DEFAULT_RPC_TIMEOUT)
+            .await?
+        {
+            Response::Lobbies { names } => Ok(names),
+            _ => Err(CommonError::protocol("unexpected response").into()),
+        }
+    }
+
+    pub async fn lobby_members(&self, name: &str) -> Result<Vec<Bytes>, ClientError> {
+        let request = Request::LobbyMembers {
+            name: name.to_string(),
+        };
+
+        match self.request(request, DEFAULT_RPC_TIMEOUT).await? {
+            Response::Members { pubkeys } => Ok(pubkeys),
+            _ => Err(CommonError::protocol("unexpected response").into()),
+        }
+    }
+
+    /// Joins the lobby, creating it when nobody is in it yet. The server answers
+    /// with [`Message::LobbyJoined`] or [`Message::LobbyRejected`].
+    pub async fn join_lobby(&self, name: &str) -> Result<(), ClientError> {
+        self.send(Message::JoinLobbby {
+            name: name.to_string(),
+        })
+        .await
+    }
+
+    pub async fn leave_lobby(&self, name: &str) -> Result<(), ClientError> {
+        self.send(Message::LeaveLobby {
+            name: name.to_string(),
+        })
+        .await
+    }
+
+    /// Says something in a lobby this client is a member of. Everybody in it,
+    /// this client included, gets it back as [`Message::ChatMessage`], unless
+    /// the server answers with [`Message::ChatRejected`].
+    pub async fn chat(&self, lobby: &str, text: &str) -> Result<(), ClientError> {
+        self.send(Message::Chat {
+            lobby: lobby.to_string(),
+            text: text.to_string(),
+        })
+        .await
+    }
+
+    async fn send(&self, msg: Message) -> Result<(), ClientError> {
+        self.txch
+            .send(msg)
+            .await
+            .map_err(|_| ClientError::internal("network task is gone"))
     }
 }
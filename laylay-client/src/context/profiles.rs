@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+
+use laylay_common::{FileKeyStore, KeyStore, SecretKey};
+
+use crate::errors::ClientError;
+
+use super::known_servers::KnownServers;
+
+/// Lives in the data folder itself, so keys from before profiles keep working.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Named local identities, every other profile has its own folder below `profiles/`.
+pub struct Profiles {
+    root: PathBuf,
+    passphrase: Option<String>,
+}
+
+impl Profiles {
+    /// `passphrase` seals the keys of all profiles.
+    pub fn new(root: PathBuf, passphrase: Option<String>) -> Self {
+        Self { root, passphrase }
+    }
+
+    fn folder(&self, name: &str) -> Result<PathBuf, ClientError> {
+        if name == DEFAULT_PROFILE {
+            return Ok(self.root.clone());
+        }
+
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        if !valid {
+            return Err(ClientError::internal(&format!(
+                "invalid profile name: {name}"
+            )));
+        }
+
+        Ok(self.root.join("profiles").join(name))
+    }
+
+    pub fn list(&self) -> Result<Vec<String>, ClientError> {
+        let mut names = vec![DEFAULT_PROFILE.to_string()];
+        let folder = self.root.join("profiles");
+
+        if folder.exists() {
+            for entry in std::fs::read_dir(folder)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    names.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// Loads the profile, a new one gets a fresh key and default settings.
+    pub fn open(&self, name: &str) -> Result<Profile, ClientError> {
+        let folder = self.folder(name)?;
+
+        if !folder.exists() {
+            std::fs::create_dir_all(&folder)?;
+        }
+
+        let prikey = FileKeyStore::new(folder.join("prikey.bin"), self.passphrase.clone())
+            .load_or_create()?;
+        let settings = Settings::load(&folder)?;
+
+        Ok(Profile {
+            name: name.to_string(),
+            folder,
+            prikey,
+            settings,
+        })
+    }
+
+    /// Deletes the profile together with its key, the default one cannot be removed.
+    pub fn remove(&self, name: &str) -> Result<(), ClientError> {
+        if name == DEFAULT_PROFILE {
+            return Err(ClientError::internal(
+                "the default profile cannot be removed",
+            ));
+        }
+
+        std::fs::remove_dir_all(self.folder(name)?)?;
+
+        if self.selected()? == name {
+            self.select(DEFAULT_PROFILE)?;
+        }
+
+        Ok(())
+    }
+
+    /// The profile used at startup when none is asked for, the last one selected.
+    pub fn selected(&self) -> Result<String, ClientError> {
+        let filename = self.root.join("profile.txt");
+
+        if filename.exists() {
+            let name = std::fs::read_to_string(filename)?;
+            Ok(name.trim().to_string())
+        } else {
+            Ok(DEFAULT_PROFILE.to_string())
+        }
+    }
+
+    pub fn select(&self, name: &str) -> Result<(), ClientError> {
+        self.folder(name)?;
+        std::fs::write(self.root.join("profile.txt"), name)?;
+
+        Ok(())
+    }
+}
+
+/// One identity with its own key, settings and pinned servers.
+pub struct Profile {
+    pub name: String,
+    folder: PathBuf,
+    prikey: SecretKey,
+    pub settings: Settings,
+}
+
+impl Profile {
+    pub fn prikey(&self) -> &SecretKey {
+        &self.prikey
+    }
+
+    pub fn known_servers(&self) -> Result<KnownServers, ClientError> {
+        KnownServers::load(self.folder.clone())
+    }
+
+    pub fn save_settings(&self) -> Result<(), ClientError> {
+        self.settings.save(&self.folder)
+    }
+}
+
+/// Stored as `key value` lines in `settings.txt` of the profile.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Address of the server as `host:port`, or `ws://host:port/path` for WebSocket.
+    pub server: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let host = if cfg!(target_os = "android") {
+            "192.168.1.9"
+        } else {
+            "127.0.0.1"
+        };
+
+        Self {
+            server: format!("{host}:33033"),
+        }
+    }
+}
+
+impl Settings {
+    fn load(folder: &Path) -> Result<Self, ClientError> {
+        let filename = folder.join("settings.txt");
+        let mut settings = Self::default();
+
+        if filename.exists() {
+            let data = std::fs::read_to_string(&filename)?;
+
+            for line in data.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                match line.split_once(' ') {
+                    Some(("server", value)) => settings.server = value.trim().to_string(),
+                    _ => tracing::warn!("unknown setting in {}: {line}", filename.display()),
+                }
+            }
+        }
+
+        Ok(settings)
+    }
+
+    fn save(&self, folder: &Path) -> Result<(), ClientError> {
+        let data = format!("server {}\n", self.server);
+
+        std::fs::write(folder.join("settings.txt"), data)?;
+
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use std::{backtrace::Backtrace, error::Error, fmt::Display};
 
+use laylay_common::{Bytes, CommonError, RpcError};
 use openxr::LoadError;
 use tracing::subscriber::SetGlobalDefaultError;
 
@@ -9,15 +10,67 @@ pub enum ClientErrorKind {
     Xr,
     Internal,
     Tracing,
+    Rejected,
+    ServerKeyChanged,
+    Protocol,
+    Crypto,
+    Rpc,
+    KeyStore,
 }
 
 #[derive(Debug)]
 pub struct ClientError {
     kind: ClientErrorKind,
     msg: String,
+    /// The key a server presented instead of the pinned one.
+    offered: Option<Bytes>,
     backtrace: Backtrace,
 }
 
+impl ClientError {
+    pub fn internal(msg: &str) -> Self {
+        Self {
+            kind: ClientErrorKind::Internal,
+            msg: msg.to_string(),
+            offered: None,
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    pub fn rejected(msg: &str) -> Self {
+        Self {
+            kind: ClientErrorKind::Rejected,
+            msg: msg.to_string(),
+            offered: None,
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    pub fn server_key_changed(msg: &str, offered: Bytes) -> Self {
+        Self {
+            kind: ClientErrorKind::ServerKeyChanged,
+            msg: msg.to_string(),
+            offered: Some(offered),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    /// The key the server presented when it was not the pinned one, pinning it with
+    /// [`crate::context::known_servers::KnownServers::accept`] trusts it from then on.
+    pub fn offered_key(&self) -> Option<&Bytes> {
+        self.offered.as_ref()
+    }
+
+    /// Errors that will not go away by trying again. A changed server key does once
+    /// it is accepted, see [`crate::context::network::Network::accept_server_key`].
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self.kind,
+            ClientErrorKind::Rejected | ClientErrorKind::KeyStore
+        )
+    }
+}
+
 impl Error for ClientError {}
 
 impl Display for ClientError {
@@ -31,16 +84,47 @@ impl From<Box<dyn std::error::Error>> for ClientError {
         Self {
             kind: ClientErrorKind::Internal,
             msg: value.to_string(),
+            offered: None,
             backtrace: Backtrace::force_capture(),
         }
     }
 }
 
+impl From<CommonError> for ClientError {
+    fn from(value: CommonError) -> Self {
+        let kind = match &value {
+            CommonError::Io(_) => ClientErrorKind::Io,
+            CommonError::Crypto(_) | CommonError::Channel(_) => ClientErrorKind::Crypto,
+            CommonError::Decode(_) | CommonError::Frame(_) | CommonError::Protocol(_) => {
+                ClientErrorKind::Protocol
+            }
+            CommonError::Rejected { reason } => return Self::rejected(reason),
+            CommonError::Rpc(_) => ClientErrorKind::Rpc,
+            CommonError::KeyStore(_) => ClientErrorKind::KeyStore,
+            CommonError::Platform(_) => ClientErrorKind::Internal,
+        };
+
+        Self {
+            kind,
+            msg: value.to_string(),
+            offered: None,
+            backtrace: Backtrace::capture(),
+        }
+    }
+}
+
+impl From<RpcError> for ClientError {
+    fn from(value: RpcError) -> Self {
+        CommonError::Rpc(value).into()
+    }
+}
+
 impl From<std::io::Error> for ClientError {
     fn from(value: std::io::Error) -> Self {
         Self {
             kind: ClientErrorKind::Io,
             msg: value.to_string(),
+            offered: None,
             backtrace: Backtrace::capture(),
         }
     }
@@ -51,6 +135,7 @@ impl From<LoadError> for ClientError {
         Self {
             kind: ClientErrorKind::Xr,
             msg: value.to_string(),
+            offered: None,
             backtrace: Backtrace::capture(),
         }
     }
@@ -61,6 +146,7 @@ impl From<openxr::sys::Result> for ClientError {
         Self {
             kind: ClientErrorKind::Xr,
             msg: value.to_string(),
+            offered: None,
             backtrace: Backtrace::capture(),
         }
     }
@@ -71,6 +157,7 @@ impl From<SetGlobalDefaultError> for ClientError {
         Self {
             kind: ClientErrorKind::Tracing,
             msg: value.to_string(),
+            offered: None,
             backtrace: Backtrace::capture(),
         }
     }
@@ -3,8 +3,8 @@ use std::sync::{
     Arc,
 };
 
-use laylay_common::Message;
-use tokio::{runtime::Runtime, sync::mpsc::Sender};
+use laylay_common::{Clock, Message, Outbox};
+use tokio::runtime::Runtime;
 use tracing::{
     field::{Field, Visit},
     span, Subscriber,
@@ -15,7 +15,9 @@ use crate::app::CTX;
 pub struct Logger {
     span_id_pool: AtomicU64,
     runtime: Arc<Runtime>,
-    txch: Sender<Message>,
+    txch: Outbox,
+    /// Stamps every line with the server time once it is known.
+    clock: Arc<Clock>,
 }
 
 struct FieldCollect {
@@ -29,11 +31,12 @@ impl Visit for FieldCollect {
 }
 
 impl Logger {
-    pub fn new(txch: Sender<Message>) -> Self {
+    pub fn new(txch: Outbox, clock: Arc<Clock>) -> Self {
         let ctx = CTX.get().unwrap();
         Self {
             runtime: ctx.runtime.clone(),
             txch,
+            clock,
             span_id_pool: AtomicU64::new(1),
         }
     }
@@ -63,15 +66,19 @@ impl Subscriber for Logger {
         event.record(&mut data);
 
         let level = meta.level().as_str().to_string();
+        let msg = data.data.join(" ");
+        let msg = match self.clock.server_time() {
+            Some(time) => Message::TimedLog {
+                time,
+                msg,
+                level,
+                target,
+            },
+            None => Message::Log { msg, level, target },
+        };
         let txch = self.txch.clone();
         self.runtime.spawn(async move {
-            let ret = txch
-                .send(Message::Log {
-                    msg: data.data.join(" "),
-                    level,
-                    target,
-                })
-                .await;
+            let ret = txch.send(msg).await;
             if let Err(e) = ret {
                 tracing::error!("{e}");
             }
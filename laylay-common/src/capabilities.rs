@@ -0,0 +1,78 @@
+use std::fmt::Display;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Optional protocol features, only used when both sides announce them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const LOGS: Capabilities = Capabilities(1 << 0);
+    pub const LOBBIES: Capabilities = Capabilities(1 << 1);
+    pub const RPC: Capabilities = Capabilities(1 << 2);
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 3);
+    pub const RESUME: Capabilities = Capabilities(1 << 4);
+    pub const REKEY: Capabilities = Capabilities(1 << 5);
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 6);
+    pub const DATAGRAM: Capabilities = Capabilities(1 << 7);
+    pub const CLOCK: Capabilities = Capabilities(1 << 8);
+
+    const NAMES: &'static [(Capabilities, &'static str)] = &[
+        (Self::LOGS, "logs"),
+        (Self::LOBBIES, "lobbies"),
+        (Self::RPC, "rpc"),
+        (Self::HEARTBEAT, "heartbeat"),
+        (Self::RESUME, "resume"),
+        (Self::REKEY, "rekey"),
+        (Self::COMPRESSION, "compression"),
+        (Self::DATAGRAM, "datagram"),
+        (Self::CLOCK, "clock"),
+    ];
+
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    /// Everything this build knows how to speak.
+    pub const fn supported() -> Self {
+        Capabilities(
+            Self::LOGS.0
+                | Self::LOBBIES.0
+                | Self::RPC.0
+                | Self::HEARTBEAT.0
+                | Self::RESUME.0
+                | Self::REKEY.0
+                | Self::COMPRESSION.0
+                | Self::DATAGRAM.0
+                | Self::CLOCK.0,
+        )
+    }
+
+    pub const fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(&self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub fn insert(&mut self, other: Capabilities) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Capabilities) {
+        self.0 &= !other.0;
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(cap, _)| self.contains(*cap))
+            .map(|(_, name)| *name)
+            .collect();
+
+        write!(f, "[{}]", names.join(", "))
+    }
+}
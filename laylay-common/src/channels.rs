@@ -0,0 +1,161 @@
+use tokio::sync::mpsc::{
+    self,
+    error::{SendError, TrySendError},
+    Receiver, Sender,
+};
+
+use crate::Message;
+
+/// Logical lanes over one connection, the writer always empties the more
+/// urgent ones first so a log burst cannot hold up anything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Protocol housekeeping and requests, small and latency sensitive.
+    Control,
+    /// State that is outdated quickly, like poses.
+    Realtime,
+    /// Logs, chat and everything large that can wait.
+    Bulk,
+}
+
+impl Channel {
+    pub fn of(msg: &Message) -> Self {
+        match msg {
+            Message::Log { .. }
+            | Message::TimedLog { .. }
+            | Message::Chat { .. }
+            | Message::ChatMessage { .. } => Channel::Bulk,
+            _ => Channel::Control,
+        }
+    }
+}
+
+/// Sending side of the per channel queues, cheap to clone.
+#[derive(Clone)]
+pub struct Outbox {
+    control: Sender<Message>,
+    realtime: Sender<Message>,
+    bulk: Sender<Message>,
+}
+
+impl Outbox {
+    /// Every channel gets its own queue holding up to `capacity` messages.
+    pub fn new(capacity: usize) -> (Outbox, Inbox) {
+        let (control, control_rx) = mpsc::channel(capacity);
+        let (realtime, realtime_rx) = mpsc::channel(capacity);
+        let (bulk, bulk_rx) = mpsc::channel(capacity);
+
+        (
+            Outbox {
+                control,
+                realtime,
+                bulk,
+            },
+            Inbox {
+                control: control_rx,
+                realtime: realtime_rx,
+                bulk: bulk_rx,
+            },
+        )
+    }
+
+    /// Queues `msg` on the channel it belongs to, see [`Channel::of`].
+    pub async fn send(&self, msg: Message) -> Result<(), SendError<Message>> {
+        self.send_on(Channel::of(&msg), msg).await
+    }
+
+    pub async fn send_on(&self, channel: Channel, msg: Message) -> Result<(), SendError<Message>> {
+        match channel {
+            Channel::Control => self.control.send(msg).await,
+            Channel::Realtime => self.realtime.send(msg).await,
+            Channel::Bulk => self.bulk.send(msg).await,
+        }
+    }
+
+    /// Like [`Outbox::send`] but fails right away instead of waiting for room,
+    /// the message is dropped then.
+    pub fn try_send(&self, msg: Message) -> Result<(), TrySendError<()>> {
+        let ret = match Channel::of(&msg) {
+            Channel::Control => self.control.try_send(msg),
+            Channel::Realtime => self.realtime.try_send(msg),
+            Channel::Bulk => self.bulk.try_send(msg),
+        };
+
+        ret.map_err(|e| match e {
+            TrySendError::Full(_) => TrySendError::Full(()),
+            TrySendError::Closed(_) => TrySendError::Closed(()),
+        })
+    }
+}
+
+/// Receiving side for the writer task.
+pub struct Inbox {
+    control: Receiver<Message>,
+    realtime: Receiver<Message>,
+    bulk: Receiver<Message>,
+}
+
+impl Inbox {
+    /// The next message of the most urgent channel that has one, `None` once
+    /// every [`Outbox`] is gone.
+    pub async fn recv(&mut self) -> Option<Message> {
+        tokio::select! {
+            biased;
+            Some(msg) = self.control.recv() => Some(msg),
+            Some(msg) = self.realtime.recv() => Some(msg),
+            Some(msg) = self.bulk.recv() => Some(msg),
+            else => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log() -> Message {
+        Message::Log {
+            msg: "x".to_string(),
+            level: "INFO".to_string(),
+            target: "test".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn urgent_channels_first() {
+        let (outbox, mut inbox) = Outbox::new(4);
+
+        outbox.send(log()).await.unwrap();
+        outbox
+            .send_on(Channel::Realtime, Message::Ping { sent: 2 })
+            .await
+            .unwrap();
+        outbox.send(Message::Pong { sent: 1 }).await.unwrap();
+
+        assert!(matches!(
+            inbox.recv().await,
+            Some(Message::Pong { sent: 1 })
+        ));
+        assert!(matches!(
+            inbox.recv().await,
+            Some(Message::Ping { sent: 2 })
+        ));
+        assert!(matches!(inbox.recv().await, Some(Message::Log { .. })));
+
+        drop(outbox);
+        assert!(inbox.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn try_send_does_not_wait() {
+        let (outbox, _inbox) = Outbox::new(1);
+
+        outbox.try_send(log()).unwrap();
+        assert!(matches!(
+            outbox.try_send(log()),
+            Err(TrySendError::Full(()))
+        ));
+        // Another channel still has room.
+        outbox.try_send(Message::Pong { sent: 1 }).unwrap();
+    }
+}
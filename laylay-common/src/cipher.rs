@@ -0,0 +1,169 @@
+use std::{error::Error, fmt::Display};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// Which way a frame travels, mixed into every nonce so the two directions
+/// of a session never produce the same nonce for the same sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Direction {
+    fn tag(&self) -> [u8; 4] {
+        match self {
+            Direction::ClientToServer => *b"c2s\0",
+            Direction::ServerToClient => *b"s2c\0",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ChannelError {
+    /// The frame failed authentication, it was modified or sealed with another key.
+    Tampered,
+    /// The frame is not the next one in sequence, it was replayed, reordered or dropped.
+    Replay { expected: u64, received: u64 },
+    /// The sequence counter ran out, the session has to be renegotiated.
+    Exhausted,
+}
+
+impl Display for ChannelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelError::Tampered => write!(f, "frame failed authentication"),
+            ChannelError::Replay { expected, received } => {
+                write!(
+                    f,
+                    "frame out of sequence: expected {expected} got {received}"
+                )
+            }
+            ChannelError::Exhausted => write!(f, "sequence counter exhausted"),
+        }
+    }
+}
+
+impl Error for ChannelError {}
+
+fn nonce(direction: Direction, seq: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&direction.tag());
+    nonce[4..].copy_from_slice(&seq.to_be_bytes());
+    nonce
+}
+
+/// The key that follows `key` after a rekey, both ends derive the same one.
+fn next_key(key: &[u8; 32]) -> [u8; 32] {
+    let mut next = [0u8; 32];
+    Hkdf::<Sha256>::new(None, key)
+        .expand(b"laylay rekey", &mut next)
+        .expect("32 bytes is a valid hkdf output length");
+    next
+}
+
+/// Encrypts the outgoing frames of one direction with AES-256-GCM.
+pub struct Sealer {
+    key: [u8; 32],
+    cipher: Aes256Gcm,
+    direction: Direction,
+    seq: u64,
+}
+
+impl Sealer {
+    pub fn new(key: &[u8; 32], direction: Direction) -> Self {
+        Self {
+            key: *key,
+            cipher: Aes256Gcm::new(key.into()),
+            direction,
+            seq: 0,
+        }
+    }
+
+    /// Moves on to the next key and starts counting from zero again.
+    pub fn rekey(&mut self) {
+        *self = Self::new(&next_key(&self.key), self.direction);
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> Result<(u64, Vec<u8>), ChannelError> {
+        let seq = self.seq;
+        self.seq = seq.checked_add(1).ok_or(ChannelError::Exhausted)?;
+
+        let nonce = nonce(self.direction, seq);
+        let encrypted = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| ChannelError::Tampered)?;
+
+        Ok((seq, encrypted))
+    }
+}
+
+/// Decrypts the incoming frames of one direction and only accepts them in
+/// strictly increasing sequence order.
+pub struct Opener {
+    key: [u8; 32],
+    cipher: Aes256Gcm,
+    direction: Direction,
+    seq: u64,
+}
+
+impl Opener {
+    pub fn new(key: &[u8; 32], direction: Direction) -> Self {
+        Self {
+            key: *key,
+            cipher: Aes256Gcm::new(key.into()),
+            direction,
+            seq: 0,
+        }
+    }
+
+    /// Moves on to the next key and starts counting from zero again.
+    pub fn rekey(&mut self) {
+        *self = Self::new(&next_key(&self.key), self.direction);
+    }
+
+    pub fn open(&mut self, seq: u64, data: &[u8]) -> Result<Vec<u8>, ChannelError> {
+        if seq != self.seq {
+            return Err(ChannelError::Replay {
+                expected: self.seq,
+                received: seq,
+            });
+        }
+
+        let nonce = nonce(self.direction, seq);
+        let decrypted = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| ChannelError::Tampered)?;
+
+        self.seq = seq.checked_add(1).ok_or(ChannelError::Exhausted)?;
+
+        Ok(decrypted)
+    }
+
+    /// Like [`Opener::open`] but gaps are fine, only frames older than the
+    /// newest one opened so far are refused. Meant for unreliable transports.
+    pub fn open_latest(&mut self, seq: u64, data: &[u8]) -> Result<Vec<u8>, ChannelError> {
+        if seq < self.seq {
+            return Err(ChannelError::Replay {
+                expected: self.seq,
+                received: seq,
+            });
+        }
+
+        // A forged frame must not move the window forward.
+        let expected = std::mem::replace(&mut self.seq, seq);
+        let ret = self.open(seq, data);
+        if ret.is_err() {
+            self.seq = expected;
+        }
+
+        ret
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Message, Outbox};
+
+/// Round trips kept for the estimate, the oldest is dropped first.
+const SAMPLES: usize = 8;
+/// Drift is only estimated over samples at least this many microseconds apart, over a few
+/// seconds network jitter dwarfs any real difference in clock speed.
+const MIN_DRIFT_SPAN: u64 = 5 * 60 * 1_000_000;
+/// Real clocks differ by tens of ppm, an estimate beyond this is noise.
+const MAX_DRIFT_PPM: f64 = 200.0;
+
+/// Microseconds since the unix epoch on this machine.
+pub fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClockConfig {
+    /// How often the clock is synchronised once the first samples are in.
+    pub interval: Duration,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// One [`Message::TimeRequest`] round trip.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// When the answer arrived, on our clock.
+    local: u64,
+    /// Server clock minus ours.
+    offset: i64,
+    /// Round trip without the time the server spent answering.
+    delay: u64,
+}
+
+/// NTP style estimate of the server clock. The offset comes from the sample
+/// with the shortest round trip, as that one was least skewed by queueing,
+/// the drift from how the offsets change over time.
+#[derive(Default)]
+pub struct Clock {
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self) -> Message {
+        Message::TimeRequest {
+            sent: unix_micros(),
+        }
+    }
+
+    /// Records the answer to a [`Clock::request`], returns its round trip.
+    pub fn response(&self, sent: u64, received: u64, replied: u64) -> Duration {
+        self.record(sent, received, replied, unix_micros())
+    }
+
+    fn record(&self, sent: u64, received: u64, replied: u64, now: u64) -> Duration {
+        let (sent, received, replied, now) =
+            (sent as i128, received as i128, replied as i128, now as i128);
+
+        let offset = ((received - sent) + (replied - now)) / 2;
+        let delay = ((now - sent) - (replied - received)).max(0);
+
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(Sample {
+            local: now as u64,
+            offset: offset as i64,
+            delay: delay as u64,
+        });
+
+        Duration::from_micros(delay as u64)
+    }
+
+    /// Samples the estimate is currently based on.
+    pub fn samples(&self) -> usize {
+        self.samples.lock().unwrap().len()
+    }
+
+    fn best(&self) -> Option<Sample> {
+        self.samples
+            .lock()
+            .unwrap()
+            .iter()
+            .min_by_key(|s| s.delay)
+            .copied()
+    }
+
+    /// Server clock minus ours in microseconds, `None` before the first sample.
+    pub fn offset(&self) -> Option<i64> {
+        self.best().map(|s| s.offset)
+    }
+
+    /// How much faster the server clock runs than ours, in parts per million.
+    /// `None` until there are samples far enough apart to tell.
+    pub fn drift(&self) -> Option<f64> {
+        let samples = self.samples.lock().unwrap();
+        let best = samples.iter().map(|s| s.delay).min()?;
+
+        // Round trips that queued much longer than the best one carry most of the jitter.
+        let samples: Vec<&Sample> = samples
+            .iter()
+            .filter(|s| s.delay <= best * 2 + 1_000)
+            .collect();
+        let first = samples.first()?;
+        let last = samples.last()?;
+
+        if last.local.saturating_sub(first.local) < MIN_DRIFT_SPAN {
+            return None;
+        }
+
+        // Least squares slope of the offset over time, relative to the first sample.
+        let points = samples.iter().map(|s| {
+            (
+                s.local as f64 - first.local as f64,
+                s.offset as f64 - first.offset as f64,
+            )
+        });
+        let n = samples.len() as f64;
+        let (sx, sy, sxx, sxy) = points.fold((0.0, 0.0, 0.0, 0.0), |(sx, sy, sxx, sxy), (x, y)| {
+            (sx + x, sy + y, sxx + x * x, sxy + x * y)
+        });
+        let denominator = n * sxx - sx * sx;
+
+        if samples.len() < 2 || denominator <= 0.0 {
+            return None;
+        }
+
+        let drift = (n * sxy - sx * sy) / denominator * 1e6;
+
+        Some(drift.clamp(-MAX_DRIFT_PPM, MAX_DRIFT_PPM))
+    }
+
+    /// The current time on the server in microseconds since the unix epoch,
+    /// `None` before the first sample.
+    pub fn server_time(&self) -> Option<u64> {
+        let best = self.best()?;
+        let now = unix_micros();
+        let elapsed = now.saturating_sub(best.local) as f64;
+        let drift = self.drift().unwrap_or(0.0) * elapsed / 1e6;
+
+        Some((now as i64 + best.offset + drift as i64) as u64)
+    }
+}
+
+/// Answers a [`Message::TimeRequest`] that arrived at `received`.
+pub fn time_response(sent: u64, received: u64) -> Message {
+    Message::TimeResponse {
+        sent,
+        received,
+        replied: unix_micros(),
+    }
+}
+
+/// Synchronises `clock` every `interval` until the writer side goes away. The
+/// first samples are taken a second apart to get a usable estimate quickly.
+pub async fn run_clock_sync(txch: Outbox, clock: &Clock, interval: Duration) {
+    for sent in 0.. {
+        if txch.send(clock.request()).await.is_err() {
+            break;
+        }
+
+        let wait = if sent < SAMPLES {
+            interval.min(Duration::from_secs(1))
+        } else {
+            interval
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000;
+
+    /// A round trip answered at `now` by a server `offset` ahead, taking `delay` both ways.
+    fn exchange(clock: &Clock, now: u64, offset: i64, delay: u64) -> Duration {
+        let sent = now - delay;
+        let received = (sent as i64 + delay as i64 / 2 + offset) as u64;
+        clock.record(sent, received, received, now)
+    }
+
+    #[test]
+    fn offset_from_symmetric_exchange() {
+        let clock = Clock::new();
+        assert_eq!(clock.offset(), None);
+
+        // 100us each way and 50us on the server, which runs 500us ahead.
+        let rtt = clock.record(1_000_000, 1_000_600, 1_000_650, 1_000_250);
+        assert_eq!(rtt, Duration::from_micros(200));
+        assert_eq!(clock.offset(), Some(500));
+    }
+
+    #[test]
+    fn low_delay_samples_win() {
+        let clock = Clock::new();
+        exchange(&clock, SECOND, 500, 200);
+
+        // Queued on the way out only, which skews the offset by half the extra delay.
+        clock.record(
+            2 * SECOND,
+            2 * SECOND + 50_500,
+            2 * SECOND + 50_500,
+            2 * SECOND + 50_000,
+        );
+        assert_eq!(clock.samples(), 2);
+        assert_eq!(clock.offset(), Some(500));
+    }
+
+    #[test]
+    fn drift_needs_a_long_span() {
+        let clock = Clock::new();
+        for i in 0..SAMPLES as u64 {
+            exchange(&clock, SECOND + i * 10 * SECOND, i as i64 * 1_000, 200);
+        }
+        assert_eq!(clock.drift(), None);
+    }
+
+    #[test]
+    fn drift_over_a_long_span() {
+        let clock = Clock::new();
+        // 10ppm, 600us more every minute.
+        for i in 0..SAMPLES as u64 {
+            exchange(&clock, SECOND + i * 60 * SECOND, i as i64 * 600, 200);
+        }
+        let drift = clock.drift().unwrap();
+        assert!((drift - 10.0).abs() < 0.01, "{drift}");
+
+        // A slow round trip does not pull the estimate off.
+        exchange(&clock, SECOND + 8 * 60 * SECOND, 1_000_000, 500_000);
+        let drift = clock.drift().unwrap();
+        assert!((drift - 10.0).abs() < 0.01, "{drift}");
+    }
+
+    #[test]
+    fn drift_is_clamped() {
+        let clock = Clock::new();
+        // A second more every minute, no real clock is that far off.
+        for i in 0..SAMPLES as u64 {
+            exchange(
+                &clock,
+                SECOND + i * 60 * SECOND,
+                i as i64 * SECOND as i64,
+                200,
+            );
+        }
+        assert_eq!(clock.drift(), Some(MAX_DRIFT_PPM));
+    }
+}
@@ -0,0 +1,136 @@
+use std::{error::Error, fmt::Display};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Upper bound for a single frame unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// The length prefix in front of every frame.
+pub(crate) const HEADER_SIZE: usize = 4;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(std::io::Error),
+    /// The peer announced, or we tried to send, a frame above the limit.
+    Oversize {
+        size: usize,
+        max: usize,
+    },
+    /// The stream ended in the middle of a frame.
+    Truncated {
+        expected: usize,
+        received: usize,
+    },
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{e}"),
+            FrameError::Oversize { size, max } => {
+                write!(f, "frame of {size} bytes exceeds the limit of {max} bytes")
+            }
+            FrameError::Truncated { expected, received } => {
+                write!(f, "stream ended after {received} of {expected} frame bytes")
+            }
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(value: std::io::Error) -> Self {
+        FrameError::Io(value)
+    }
+}
+
+/// Frames are a big endian `u32` length followed by that many bytes.
+#[derive(Debug, Clone)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let size = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+
+        if size > self.max_frame_size {
+            return Err(FrameError::Oversize {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+
+        if src.len() < HEADER_SIZE + size {
+            src.reserve(HEADER_SIZE + size - src.len());
+            return Ok(None);
+        }
+
+        src.advance(HEADER_SIZE);
+
+        Ok(Some(src.split_to(size)))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                let expected = if src.len() < HEADER_SIZE {
+                    HEADER_SIZE
+                } else {
+                    HEADER_SIZE + u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize
+                };
+
+                Err(FrameError::Truncated {
+                    expected,
+                    received: src.len(),
+                })
+            }
+        }
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if item.len() > self.max_frame_size {
+            return Err(FrameError::Oversize {
+                size: item.len(),
+                max: self.max_frame_size,
+            });
+        }
+
+        dst.reserve(HEADER_SIZE + item.len());
+        dst.put_u32(item.len() as u32);
+        dst.extend_from_slice(&item);
+
+        Ok(())
+    }
+}
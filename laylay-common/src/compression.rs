@@ -0,0 +1,170 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::CommonError;
+
+const RAW: u8 = 0;
+const ZSTD: u8 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionConfig {
+    /// Smaller messages are not worth the effort and go out as they are.
+    pub threshold: usize,
+    /// zstd level, 1 is fastest, 22 is smallest.
+    pub level: i32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 512,
+            level: 3,
+        }
+    }
+}
+
+/// What compression saved on the sending side, can be shared by several connections.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    frames: AtomicU64,
+    original: AtomicU64,
+    compressed: AtomicU64,
+}
+
+impl CompressionStats {
+    /// Frames that went out compressed.
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    /// Size of those frames before compression.
+    pub fn original(&self) -> u64 {
+        self.original.load(Ordering::Relaxed)
+    }
+
+    /// Size of those frames after compression.
+    pub fn compressed(&self) -> u64 {
+        self.compressed.load(Ordering::Relaxed)
+    }
+
+    pub fn saved(&self) -> u64 {
+        self.original().saturating_sub(self.compressed())
+    }
+}
+
+/// Prefixes every message with whether it is compressed, compressing those
+/// above the threshold when that actually makes them smaller.
+pub(crate) struct Compressor {
+    config: Option<CompressionConfig>,
+    stats: Arc<CompressionStats>,
+}
+
+impl Compressor {
+    pub fn new() -> Self {
+        Self {
+            config: None,
+            stats: Arc::new(CompressionStats::default()),
+        }
+    }
+
+    pub fn configure(&mut self, config: CompressionConfig, stats: Arc<CompressionStats>) {
+        self.config = Some(config);
+        self.stats = stats;
+    }
+
+    pub fn pack(&self, data: Vec<u8>) -> Vec<u8> {
+        if let Some(config) = self.config.filter(|c| data.len() >= c.threshold) {
+            if let Ok(compressed) = zstd::bulk::compress(&data, config.level) {
+                if compressed.len() < data.len() {
+                    self.stats.frames.fetch_add(1, Ordering::Relaxed);
+                    self.stats
+                        .original
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    self.stats
+                        .compressed
+                        .fetch_add(compressed.len() as u64, Ordering::Relaxed);
+
+                    return [&[ZSTD][..], &compressed].concat();
+                }
+            }
+        }
+
+        [&[RAW][..], &data].concat()
+    }
+}
+
+/// Reverses [`Compressor::pack`], a message may not grow beyond `max` bytes.
+pub(crate) fn unpack(data: &[u8], max: usize) -> Result<Vec<u8>, CommonError> {
+    match data.split_first() {
+        Some((&RAW, data)) => Ok(data.to_vec()),
+        Some((&ZSTD, data)) => zstd::bulk::decompress(data, max).map_err(CommonError::decode),
+        Some((kind, _)) => Err(CommonError::Decode(format!("unknown compression {kind}"))),
+        None => Err(CommonError::Decode("empty message".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressor(threshold: usize) -> (Compressor, Arc<CompressionStats>) {
+        let stats = Arc::new(CompressionStats::default());
+        let mut compressor = Compressor::new();
+        compressor.configure(
+            CompressionConfig {
+                threshold,
+                level: 3,
+            },
+            stats.clone(),
+        );
+        (compressor, stats)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (compressor, stats) = compressor(64);
+        let data = b"laylay ".repeat(100);
+
+        let packed = compressor.pack(data.clone());
+        assert_eq!(packed[0], ZSTD);
+        assert!(packed.len() < data.len());
+        assert_eq!(unpack(&packed, data.len()).unwrap(), data);
+
+        assert_eq!(stats.frames(), 1);
+        assert_eq!(stats.original(), data.len() as u64);
+        assert_eq!(stats.compressed() as usize, packed.len() - 1);
+    }
+
+    #[test]
+    fn small_or_unconfigured_stays_raw() {
+        let (compressor, stats) = compressor(64);
+        let data = b"short".to_vec();
+
+        let packed = compressor.pack(data.clone());
+        assert_eq!(packed, [&[RAW][..], &data].concat());
+        assert_eq!(unpack(&packed, data.len()).unwrap(), data);
+        assert_eq!(stats.frames(), 0);
+
+        let packed = Compressor::new().pack(b"laylay ".repeat(100));
+        assert_eq!(packed[0], RAW);
+    }
+
+    #[test]
+    fn decompression_is_bounded() {
+        let (compressor, _) = compressor(64);
+        let data = vec![0u8; 64 * 1024];
+
+        let packed = compressor.pack(data.clone());
+        assert!(packed.len() < 1024);
+        assert!(unpack(&packed, data.len() - 1).is_err());
+        assert_eq!(unpack(&packed, data.len()).unwrap().len(), data.len());
+    }
+
+    #[test]
+    fn refuses_unknown_or_empty() {
+        assert!(unpack(&[7, 1, 2, 3], 1024).is_err());
+        assert!(unpack(&[], 1024).is_err());
+    }
+}
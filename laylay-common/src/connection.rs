@@ -0,0 +1,804 @@
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use k256::SecretKey;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+use crate::{
+    compression::{unpack, Compressor},
+    random_nonce,
+    rekey::KeyUsage,
+    Capabilities, CommonError, CompressionConfig, CompressionStats, DatagramOpener, DatagramSealer,
+    FrameCodec, FrameError, Info, Message, Negotiated, Opener, Policy, RekeyConfig, Role, Sealer,
+    SessionKeys, Transcript, Version, PROTOCOL_VERSION,
+};
+
+/// What we learned about the other side during the handshake.
+#[derive(Clone)]
+pub struct Peer {
+    pub pubkey: Bytes,
+    /// The protocol and capabilities both sides agreed on.
+    pub negotiated: Negotiated,
+    pub version: Version,
+    pub info: Info,
+}
+
+type Frames<T> = Framed<T, FrameCodec>;
+
+async fn next_frame<S>(rx: &mut S) -> Result<BytesMut, CommonError>
+where
+    S: futures::Stream<Item = Result<BytesMut, FrameError>> + Unpin,
+{
+    match rx.next().await {
+        Some(frame) => Ok(frame?),
+        None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+    }
+}
+
+/// Sends a plain handshake message and returns its encoding for the transcript.
+async fn write_plain<T>(tx: &mut Frames<T>, msg: &Message) -> Result<Vec<u8>, CommonError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let data = borsh::to_vec(msg).map_err(CommonError::decode)?;
+
+    tx.send(Bytes::copy_from_slice(&data)).await?;
+
+    Ok(data)
+}
+
+/// Receives a plain handshake message together with its encoding for the transcript.
+async fn read_plain<T>(rx: &mut Frames<T>) -> Result<(Message, Vec<u8>), CommonError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let buffer = next_frame(rx).await?.to_vec();
+
+    let msg = borsh::from_slice(&buffer).map_err(CommonError::decode)?;
+
+    Ok((msg, buffer))
+}
+
+async fn reject<T>(tx: &mut Frames<T>, reason: String) -> CommonError
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let msg = Message::Rejected {
+        reason: reason.clone(),
+    };
+
+    if let Err(e) = write_plain(tx, &msg).await {
+        return e;
+    }
+
+    CommonError::rejected(reason)
+}
+
+/// Variant index of [`Message::Greeting`] in its encoding.
+const GREETING: u8 = 0;
+
+fn greeting(prikey: &SecretKey, info: Info, policy: &Policy) -> Message {
+    Message::Greeting {
+        protocol: PROTOCOL_VERSION,
+        pubkey: prikey.public_key().to_sec1_bytes().into(),
+        capabilities: policy.capabilities,
+        nonce: random_nonce(),
+        version: Version::get(),
+        info,
+    }
+}
+
+struct Greeting {
+    pubkey: Bytes,
+    protocol: u32,
+    capabilities: Capabilities,
+    version: Version,
+    info: Info,
+}
+
+fn peer(msg: Message) -> Result<Greeting, CommonError> {
+    match msg {
+        Message::Greeting {
+            protocol,
+            pubkey,
+            capabilities,
+            nonce: _,
+            version,
+            info,
+        } => Ok(Greeting {
+            pubkey,
+            protocol,
+            capabilities,
+            version,
+            info,
+        }),
+        _ => Err(CommonError::protocol("expected greeting")),
+    }
+}
+
+/// Decodes the peer's greeting. When it does not decode, but still starts with a protocol
+/// version `policy` refuses, that is reported as [`CommonError::Rejected`] instead.
+fn decode_greeting(data: &[u8], policy: &Policy) -> Result<Greeting, CommonError> {
+    let e = match borsh::from_slice(data) {
+        Ok(msg) => return peer(msg),
+        Err(e) => CommonError::decode(e),
+    };
+
+    match data {
+        [GREETING, a, b, c, d, ..] => {
+            let protocol = u32::from_le_bytes([*a, *b, *c, *d]);
+            policy.protocol(protocol).map_err(CommonError::rejected)?;
+            Err(e)
+        }
+        _ => Err(e),
+    }
+}
+
+/// An authenticated, encrypted message stream over any async transport.
+///
+/// The handshake exchanges greetings, lets both sides sign the transcript and
+/// derives the session keys, see [`Connection::accept`] and [`Connection::connect`].
+pub struct Connection<T> {
+    frames: Frames<T>,
+    outgoing: Outgoing,
+    incoming: Incoming,
+    /// Keys for [`Connection::datagrams`], until they are taken.
+    datagram: Option<(Sealer, Opener)>,
+    peer: Peer,
+}
+
+/// Datagram keys of `role`, only when both sides announced [`Capabilities::DATAGRAM`].
+fn datagram(keys: &SessionKeys, role: Role, negotiated: &Negotiated) -> Option<(Sealer, Opener)> {
+    if negotiated.capabilities.contains(Capabilities::DATAGRAM) {
+        let keys = keys.datagram();
+        Some((keys.sealer(role), keys.opener(role)))
+    } else {
+        None
+    }
+}
+
+impl<T> Connection<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Server side of the handshake, clients not matching `policy` are rejected.
+    pub async fn accept(
+        io: T,
+        prikey: &SecretKey,
+        info: Info,
+        codec: FrameCodec,
+        policy: &Policy,
+    ) -> Result<Self, CommonError> {
+        let mut frames = Framed::new(io, codec);
+        let mut transcript = Transcript::new();
+
+        let greeting = write_plain(&mut frames, &greeting(prikey, info, policy)).await?;
+        transcript.set(Role::Server, greeting);
+
+        let greeting = next_frame(&mut frames).await?.to_vec();
+        let Greeting {
+            pubkey,
+            protocol,
+            capabilities,
+            version,
+            info,
+        } = match decode_greeting(&greeting, policy) {
+            Err(CommonError::Rejected { reason }) => return Err(reject(&mut frames, reason).await),
+            peer => peer?,
+        };
+        transcript.set(Role::Client, greeting);
+
+        let negotiated = match policy.negotiate(protocol, &version, capabilities) {
+            Ok(negotiated) => negotiated,
+            Err(reason) => return Err(reject(&mut frames, reason).await),
+        };
+
+        let (msg, _) = read_plain(&mut frames).await?;
+        let proven = match msg {
+            Message::Proof { signature } => {
+                transcript.verify(&pubkey, Role::Client, &signature).is_ok()
+            }
+            _ => false,
+        };
+
+        if !proven {
+            let reason = "could not prove possession of the key".to_string();
+            return Err(reject(&mut frames, reason).await);
+        }
+
+        let accepted = Message::Accepted {
+            signature: transcript.prove(prikey, Role::Server),
+        };
+        write_plain(&mut frames, &accepted).await?;
+
+        let keys = SessionKeys::derive(prikey, &pubkey, &transcript)?;
+
+        let max_frame_size = frames.codec().max_frame_size();
+
+        Ok(Self {
+            frames,
+            outgoing: Outgoing::new(keys.sealer(Role::Server), &negotiated),
+            incoming: Incoming::new(keys.opener(Role::Server), &negotiated, max_frame_size),
+            datagram: datagram(&keys, Role::Server, &negotiated),
+            peer: Peer {
+                pubkey,
+                negotiated,
+                version,
+                info,
+            },
+        })
+    }
+
+    /// Client side of the handshake, the server has proven it holds the key in [`Peer::pubkey`].
+    pub async fn connect(
+        io: T,
+        prikey: &SecretKey,
+        info: Info,
+        codec: FrameCodec,
+        policy: &Policy,
+    ) -> Result<Self, CommonError> {
+        let mut frames = Framed::new(io, codec);
+        let mut transcript = Transcript::new();
+
+        let greeting = write_plain(&mut frames, &greeting(prikey, info, policy)).await?;
+        transcript.set(Role::Client, greeting);
+
+        let greeting = next_frame(&mut frames).await?.to_vec();
+        let Greeting {
+            pubkey,
+            protocol,
+            capabilities,
+            version,
+            info,
+        } = decode_greeting(&greeting, policy)?;
+        transcript.set(Role::Server, greeting);
+
+        let negotiated = policy
+            .negotiate(protocol, &version, capabilities)
+            .map_err(CommonError::rejected)?;
+
+        let proof = Message::Proof {
+            signature: transcript.prove(prikey, Role::Client),
+        };
+        write_plain(&mut frames, &proof).await?;
+
+        let (msg, _) = read_plain(&mut frames).await?;
+        match msg {
+            Message::Accepted { signature } => {
+                transcript
+                    .verify(&pubkey, Role::Server, &signature)
+                    .map_err(|e| CommonError::rejected(format!("server failed its proof: {e}")))?;
+            }
+            Message::Rejected { reason } => {
+                return Err(CommonError::Rejected { reason });
+            }
+            _ => {
+                return Err(CommonError::protocol("expected accepted or rejected"));
+            }
+        }
+
+        let keys = SessionKeys::derive(prikey, &pubkey, &transcript)?;
+
+        let max_frame_size = frames.codec().max_frame_size();
+
+        Ok(Self {
+            frames,
+            outgoing: Outgoing::new(keys.sealer(Role::Client), &negotiated),
+            incoming: Incoming::new(keys.opener(Role::Client), &negotiated, max_frame_size),
+            datagram: datagram(&keys, Role::Client, &negotiated),
+            peer: Peer {
+                pubkey,
+                negotiated,
+                version,
+                info,
+            },
+        })
+    }
+
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    /// Sends a [`Message::Rekey`] and switches to the next key whenever `config`
+    /// says so. Does nothing unless both sides announced [`Capabilities::REKEY`].
+    pub fn set_rekey(&mut self, config: RekeyConfig) {
+        if self
+            .peer
+            .negotiated
+            .capabilities
+            .contains(Capabilities::REKEY)
+        {
+            self.outgoing.usage = Some(KeyUsage::new(config));
+        }
+    }
+
+    /// Compresses messages above the threshold from now on and counts the savings
+    /// in `stats`. Does nothing unless both sides announced [`Capabilities::COMPRESSION`].
+    pub fn set_compression(&mut self, config: CompressionConfig, stats: Arc<CompressionStats>) {
+        if let Some(compressor) = &mut self.outgoing.compressor {
+            compressor.configure(config, stats);
+        }
+    }
+
+    /// Seals and opens datagrams announced with [`Message::Datagram`] under `id`. Only
+    /// available once and unless both sides announced [`Capabilities::DATAGRAM`].
+    pub fn datagrams(&mut self, id: u64) -> Option<(DatagramSealer, DatagramOpener)> {
+        self.datagram
+            .take()
+            .map(|(sealer, opener)| (DatagramSealer::new(id, sealer), DatagramOpener::new(opener)))
+    }
+
+    pub async fn send(&mut self, msg: &Message) -> Result<(), CommonError> {
+        self.outgoing.write(&mut self.frames, msg).await
+    }
+
+    pub async fn recv(&mut self) -> Result<Message, CommonError> {
+        self.incoming.read(&mut self.frames).await
+    }
+
+    /// Splits into halves that can live in separate reader and writer tasks.
+    pub fn split(self) -> (ConnectionWriter<T>, ConnectionReader<T>) {
+        let (sink, stream) = self.frames.split();
+
+        (
+            ConnectionWriter {
+                sink,
+                outgoing: self.outgoing,
+            },
+            ConnectionReader {
+                stream,
+                incoming: self.incoming,
+            },
+        )
+    }
+}
+
+pub struct ConnectionWriter<T> {
+    sink: SplitSink<Frames<T>, Bytes>,
+    outgoing: Outgoing,
+}
+
+impl<T> ConnectionWriter<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn send(&mut self, msg: &Message) -> Result<(), CommonError> {
+        self.outgoing.write(&mut self.sink, msg).await
+    }
+}
+
+pub struct ConnectionReader<T> {
+    stream: SplitStream<Frames<T>>,
+    incoming: Incoming,
+}
+
+impl<T> ConnectionReader<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn recv(&mut self) -> Result<Message, CommonError> {
+        self.incoming.read(&mut self.stream).await
+    }
+}
+
+/// Everything that turns a message into a frame.
+struct Outgoing {
+    sealer: Sealer,
+    usage: Option<KeyUsage>,
+    compressor: Option<Compressor>,
+}
+
+impl Outgoing {
+    fn new(sealer: Sealer, negotiated: &Negotiated) -> Self {
+        let compressed = negotiated.capabilities.contains(Capabilities::COMPRESSION);
+
+        Self {
+            sealer,
+            usage: None,
+            compressor: compressed.then(Compressor::new),
+        }
+    }
+
+    async fn write<S>(&mut self, tx: &mut S, msg: &Message) -> Result<(), CommonError>
+    where
+        S: futures::Sink<Bytes, Error = FrameError> + Unpin,
+    {
+        let len = self.write_sealed(tx, msg).await?;
+
+        let due = self.usage.as_mut().is_some_and(|usage| usage.record(len));
+
+        if due {
+            // The peer switches when it opens this, so nothing sealed before is lost.
+            self.write_sealed(tx, &Message::Rekey).await?;
+            self.sealer.rekey();
+
+            if let Some(usage) = &mut self.usage {
+                usage.reset();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Seals and sends a single frame, returns its size.
+    async fn write_sealed<S>(&mut self, tx: &mut S, msg: &Message) -> Result<usize, CommonError>
+    where
+        S: futures::Sink<Bytes, Error = FrameError> + Unpin,
+    {
+        let data = borsh::to_vec(msg).map_err(CommonError::decode)?;
+        let data = match &self.compressor {
+            Some(compressor) => compressor.pack(data),
+            None => data,
+        };
+
+        let (seq, encrypted) = self.sealer.seal(&data)?;
+
+        let mut frame = BytesMut::with_capacity(8 + encrypted.len());
+        frame.put_u64(seq);
+        frame.extend_from_slice(&encrypted);
+
+        let len = frame.len();
+        tx.send(frame.freeze()).await?;
+
+        Ok(len)
+    }
+}
+
+/// Everything that turns a frame back into a message.
+struct Incoming {
+    opener: Opener,
+    /// Limit for decompressed messages, `None` when compression was not negotiated.
+    unpack: Option<usize>,
+}
+
+impl Incoming {
+    fn new(opener: Opener, negotiated: &Negotiated, max_frame_size: usize) -> Self {
+        let compressed = negotiated.capabilities.contains(Capabilities::COMPRESSION);
+
+        Self {
+            opener,
+            unpack: compressed.then_some(max_frame_size),
+        }
+    }
+
+    async fn read<S>(&mut self, rx: &mut S) -> Result<Message, CommonError>
+    where
+        S: futures::Stream<Item = Result<BytesMut, FrameError>> + Unpin,
+    {
+        loop {
+            match self.read_sealed(rx).await? {
+                Message::Rekey => self.opener.rekey(),
+                msg => return Ok(msg),
+            }
+        }
+    }
+
+    async fn read_sealed<S>(&mut self, rx: &mut S) -> Result<Message, CommonError>
+    where
+        S: futures::Stream<Item = Result<BytesMut, FrameError>> + Unpin,
+    {
+        let frame = next_frame(rx).await?;
+
+        if frame.len() < 8 {
+            return Err(FrameError::Truncated {
+                expected: 8,
+                received: frame.len(),
+            }
+            .into());
+        }
+
+        let (seq, encrypted) = frame.split_at(8);
+        let seq = u64::from_be_bytes(seq.try_into().map_err(CommonError::decode)?);
+        let data = self.opener.open(seq, encrypted)?;
+        let data = match self.unpack {
+            Some(max) => unpack(&data, max)?,
+            None => data,
+        };
+
+        borsh::from_slice(&data).map_err(CommonError::decode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::ChannelError;
+
+    fn key() -> SecretKey {
+        SecretKey::random(&mut OsRng)
+    }
+
+    async fn accept(
+        io: DuplexStream,
+        codec: FrameCodec,
+    ) -> Result<Connection<DuplexStream>, CommonError> {
+        Connection::accept(io, &key(), Info::new()?, codec, &Policy::default()).await
+    }
+
+    async fn connect(io: DuplexStream) -> Result<Connection<DuplexStream>, CommonError> {
+        Connection::connect(
+            io,
+            &key(),
+            Info::new()?,
+            FrameCodec::default(),
+            &Policy::default(),
+        )
+        .await
+    }
+
+    /// Client and server connected through a relay that hands every client
+    /// frame after the handshake to `tamper`, which decides what the server gets.
+    async fn relayed<F>(
+        codec: FrameCodec,
+        mut tamper: F,
+    ) -> (Connection<DuplexStream>, Connection<DuplexStream>)
+    where
+        F: FnMut(Bytes) -> Vec<Bytes> + Send + 'static,
+    {
+        let (client, relay_client) = duplex(1 << 16);
+        let (relay_server, server) = duplex(1 << 16);
+
+        tokio::spawn(async move {
+            let (mut to_client, mut from_client) =
+                Framed::new(relay_client, FrameCodec::default()).split();
+            let (mut to_server, mut from_server) =
+                Framed::new(relay_server, FrameCodec::default()).split();
+
+            let upstream = async {
+                // Greeting and proof pass untouched.
+                let mut frames = 0;
+                while let Some(Ok(frame)) = from_client.next().await {
+                    frames += 1;
+                    let frames = if frames <= 2 {
+                        vec![frame.freeze()]
+                    } else {
+                        tamper(frame.freeze())
+                    };
+                    for frame in frames {
+                        if to_server.send(frame).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            };
+            let downstream = async {
+                while let Some(Ok(frame)) = from_server.next().await {
+                    if to_client.send(frame.freeze()).await.is_err() {
+                        return;
+                    }
+                }
+            };
+
+            tokio::join!(upstream, downstream);
+        });
+
+        let (client, server) = tokio::join!(connect(client), accept(server, codec));
+        (client.unwrap(), server.unwrap())
+    }
+
+    fn ping(sent: u64) -> Message {
+        Message::Ping { sent }
+    }
+
+    fn assert_ping(msg: Result<Message, CommonError>, expected: u64) {
+        match msg {
+            Ok(Message::Ping { sent }) => assert_eq!(sent, expected),
+            Ok(_) => panic!("expected a ping"),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (client, server) = duplex(1 << 16);
+        let server_key = key();
+        let pubkey = server_key.public_key().to_sec1_bytes();
+
+        let server = tokio::spawn(async move {
+            let info = Info::new().unwrap();
+            let policy = Policy::default();
+            let mut conn =
+                Connection::accept(server, &server_key, info, FrameCodec::default(), &policy)
+                    .await
+                    .unwrap();
+            let msg = conn.recv().await.unwrap();
+            conn.send(&msg).await.unwrap();
+        });
+
+        let mut conn = connect(client).await.unwrap();
+        assert_eq!(&conn.peer().pubkey[..], &pubkey[..]);
+        assert_eq!(conn.peer().negotiated.protocol, PROTOCOL_VERSION);
+
+        conn.send(&ping(7)).await.unwrap();
+        assert_ping(conn.recv().await, 7);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_proof() {
+        let (client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept(server, FrameCodec::default()));
+
+        // Greets with one key but proves with another.
+        let (greeted, proven) = (key(), key());
+        let mut frames = Framed::new(client, FrameCodec::default());
+        let mut transcript = Transcript::new();
+
+        let (_, greeting_server) = read_plain(&mut frames).await.unwrap();
+        transcript.set(Role::Server, greeting_server);
+        let msg = greeting(&greeted, Info::new().unwrap(), &Policy::default());
+        transcript.set(Role::Client, write_plain(&mut frames, &msg).await.unwrap());
+        let proof = Message::Proof {
+            signature: transcript.prove(&proven, Role::Client),
+        };
+        write_plain(&mut frames, &proof).await.unwrap();
+
+        let (msg, _) = read_plain(&mut frames).await.unwrap();
+        assert!(matches!(msg, Message::Rejected { .. }));
+        assert!(matches!(
+            server.await.unwrap(),
+            Err(CommonError::Rejected { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn too_old_protocol() {
+        let (client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept(server, FrameCodec::default()));
+        let mut frames = Framed::new(client, FrameCodec::default());
+        read_plain(&mut frames).await.unwrap();
+
+        // A greeting from before the current layout, only the version still decodes.
+        let mut old = vec![GREETING];
+        old.extend_from_slice(&0u32.to_le_bytes());
+        old.extend_from_slice(b"gone");
+        frames.send(Bytes::from(old)).await.unwrap();
+
+        let (msg, _) = read_plain(&mut frames).await.unwrap();
+        assert!(matches!(msg, Message::Rejected { reason } if reason.contains("version 0")));
+        assert!(matches!(
+            server.await.unwrap(),
+            Err(CommonError::Rejected { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn tampered_frame() {
+        let (mut client, mut server) = relayed(FrameCodec::default(), |frame| {
+            let mut frame = frame.to_vec();
+            let last = frame.len() - 1;
+            frame[last] ^= 1;
+            vec![frame.into()]
+        })
+        .await;
+
+        client.send(&ping(1)).await.unwrap();
+        assert!(matches!(
+            server.recv().await,
+            Err(CommonError::Channel(ChannelError::Tampered))
+        ));
+    }
+
+    #[tokio::test]
+    async fn replayed_frame() {
+        let (mut client, mut server) =
+            relayed(FrameCodec::default(), |frame| vec![frame.clone(), frame]).await;
+
+        client.send(&ping(1)).await.unwrap();
+        assert_ping(server.recv().await, 1);
+        assert!(matches!(
+            server.recv().await,
+            Err(CommonError::Channel(ChannelError::Replay {
+                expected: 1,
+                received: 0
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reordered_frames() {
+        let mut held = None;
+        let (mut client, mut server) =
+            relayed(FrameCodec::default(), move |frame| match held.take() {
+                None => {
+                    held = Some(frame);
+                    vec![]
+                }
+                Some(first) => vec![frame, first],
+            })
+            .await;
+
+        client.send(&ping(1)).await.unwrap();
+        client.send(&ping(2)).await.unwrap();
+        assert!(matches!(
+            server.recv().await,
+            Err(CommonError::Channel(ChannelError::Replay {
+                expected: 0,
+                received: 1
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversize_frame() {
+        let (mut client, mut server) = relayed(FrameCodec::new(4096), |frame| vec![frame]).await;
+
+        let msg = Message::Log {
+            msg: "x".repeat(8192),
+            level: "INFO".to_string(),
+            target: "test".to_string(),
+        };
+        client.send(&msg).await.unwrap();
+        assert!(matches!(
+            server.recv().await,
+            Err(CommonError::Frame(FrameError::Oversize { max: 4096, .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_frame() {
+        // Too short to even hold the sequence number.
+        let (mut client, mut server) = relayed(FrameCodec::default(), |_| {
+            vec![Bytes::from_static(&[0, 0, 0])]
+        })
+        .await;
+
+        client.send(&ping(1)).await.unwrap();
+        assert!(matches!(
+            server.recv().await,
+            Err(CommonError::Frame(FrameError::Truncated {
+                expected: 8,
+                received: 3
+            }))
+        ));
+
+        // The stream ends in the middle of a frame.
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept(server, FrameCodec::default()));
+        client.write_all(&[0, 0, 0, 10, 1, 2, 3]).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        assert!(matches!(
+            server.await.unwrap(),
+            Err(CommonError::Frame(FrameError::Truncated {
+                expected: 14,
+                received: 7
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rekey_keeps_every_frame() {
+        let (client, server) = duplex(1 << 16);
+        let (client, server) = tokio::join!(connect(client), accept(server, FrameCodec::default()));
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        let config = RekeyConfig {
+            max_bytes: u64::MAX,
+            max_messages: 3,
+            max_age: std::time::Duration::from_secs(3600),
+        };
+        client.set_rekey(config);
+        server.set_rekey(config);
+
+        let (mut tx, _rx) = client.split();
+        let sender = tokio::spawn(async move {
+            for sent in 0..50 {
+                tx.send(&ping(sent)).await.unwrap();
+            }
+        });
+
+        for sent in 0..50 {
+            assert_ping(server.recv().await, sent);
+        }
+        sender.await.unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{CommonError, FrameError, Message, Opener, Sealer};
+
+/// Largest datagram worth sending, stays below the usual path MTU so nothing
+/// gets fragmented. Bigger messages have to take the stream.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// Routing id plus sequence number in front of every datagram.
+const HEADER_SIZE: usize = 16;
+
+/// The id a datagram was sent under, tells the server which connection's keys open it.
+pub fn datagram_id(packet: &[u8]) -> Option<u64> {
+    let id = packet.get(..8)?;
+    Some(u64::from_be_bytes(id.try_into().ok()?))
+}
+
+/// Turns messages into datagrams: id, sequence number and the sealed message.
+pub struct DatagramSealer {
+    id: u64,
+    sealer: Sealer,
+}
+
+impl DatagramSealer {
+    pub fn new(id: u64, sealer: Sealer) -> Self {
+        Self { id, sealer }
+    }
+
+    pub fn seal(&mut self, msg: &Message) -> Result<Bytes, CommonError> {
+        let data = borsh::to_vec(msg).map_err(CommonError::decode)?;
+        let (seq, encrypted) = self.sealer.seal(&data)?;
+
+        let mut packet = BytesMut::with_capacity(HEADER_SIZE + encrypted.len());
+        packet.put_u64(self.id);
+        packet.put_u64(seq);
+        packet.extend_from_slice(&encrypted);
+
+        Ok(packet.freeze())
+    }
+}
+
+/// Turns datagrams back into messages, lost ones are skipped and late ones dropped.
+pub struct DatagramOpener {
+    opener: Opener,
+}
+
+impl DatagramOpener {
+    pub fn new(opener: Opener) -> Self {
+        Self { opener }
+    }
+
+    pub fn open(&mut self, packet: &[u8]) -> Result<Message, CommonError> {
+        if packet.len() < HEADER_SIZE {
+            return Err(FrameError::Truncated {
+                expected: HEADER_SIZE,
+                received: packet.len(),
+            }
+            .into());
+        }
+
+        let (header, encrypted) = packet.split_at(HEADER_SIZE);
+        let seq = u64::from_be_bytes(header[8..].try_into().map_err(CommonError::decode)?);
+        let data = self.opener.open_latest(seq, encrypted)?;
+
+        borsh::from_slice(&data).map_err(CommonError::decode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChannelError, Direction};
+
+    fn pair() -> (DatagramSealer, DatagramOpener) {
+        let key = [7u8; 32];
+        (
+            DatagramSealer::new(42, Sealer::new(&key, Direction::ClientToServer)),
+            DatagramOpener::new(Opener::new(&key, Direction::ClientToServer)),
+        )
+    }
+
+    fn ping(sealer: &mut DatagramSealer, sent: u64) -> Bytes {
+        sealer.seal(&Message::Ping { sent }).unwrap()
+    }
+
+    fn assert_ping(msg: Result<Message, CommonError>, expected: u64) {
+        match msg {
+            Ok(Message::Ping { sent }) => assert_eq!(sent, expected),
+            Ok(_) => panic!("expected a ping"),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn assert_replay(msg: Result<Message, CommonError>) {
+        assert!(matches!(
+            msg,
+            Err(CommonError::Channel(ChannelError::Replay { .. }))
+        ));
+    }
+
+    #[test]
+    fn routed_by_id() {
+        let (mut sealer, _) = pair();
+        assert_eq!(datagram_id(&ping(&mut sealer, 1)), Some(42));
+        assert_eq!(datagram_id(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn lost_datagrams_are_skipped() {
+        let (mut sealer, mut opener) = pair();
+        let packets: Vec<Bytes> = (0..4).map(|i| ping(&mut sealer, i)).collect();
+
+        assert_ping(opener.open(&packets[0]), 0);
+        assert_ping(opener.open(&packets[2]), 2);
+        assert_ping(opener.open(&packets[3]), 3);
+    }
+
+    #[test]
+    fn old_and_replayed_datagrams_are_dropped() {
+        let (mut sealer, mut opener) = pair();
+        let packets: Vec<Bytes> = (0..3).map(|i| ping(&mut sealer, i)).collect();
+
+        assert_ping(opener.open(&packets[1]), 1);
+        assert_replay(opener.open(&packets[0]));
+        assert_replay(opener.open(&packets[1]));
+        assert_ping(opener.open(&packets[2]), 2);
+    }
+
+    #[test]
+    fn forged_datagram_keeps_the_window() {
+        let (mut sealer, mut opener) = pair();
+        let packet = ping(&mut sealer, 0);
+
+        let mut forged = packet.to_vec();
+        forged[8..16].copy_from_slice(&1000u64.to_be_bytes());
+        assert!(matches!(
+            opener.open(&forged),
+            Err(CommonError::Channel(ChannelError::Tampered))
+        ));
+
+        assert_ping(opener.open(&packet), 0);
+        assert!(matches!(
+            opener.open(&packet[..10]),
+            Err(CommonError::Frame(FrameError::Truncated { .. }))
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+use std::{error::Error, fmt::Display};
+
+use crate::{ChannelError, FrameError, RpcError};
+
+#[derive(Debug)]
+pub enum CommonError {
+    Io(std::io::Error),
+    /// Malformed keys, bad signatures or failed key derivation.
+    Crypto(String),
+    /// A message that could not be encoded or decoded.
+    Decode(String),
+    /// The framing layer refused a frame.
+    Frame(FrameError),
+    /// The encrypted channel refused a frame.
+    Channel(ChannelError),
+    /// The peer sent something else than the protocol step we waited for.
+    Protocol(String),
+    /// One side refused the other, the reason is meant for humans.
+    Rejected {
+        reason: String,
+    },
+    /// A request to the peer did not get a successful response.
+    Rpc(RpcError),
+    /// The private key could not be loaded or stored.
+    KeyStore(String),
+    /// The platform could not tell us about itself.
+    Platform(String),
+}
+
+impl CommonError {
+    pub fn rejected(reason: impl Into<String>) -> Self {
+        CommonError::Rejected {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn protocol(msg: impl Into<String>) -> Self {
+        CommonError::Protocol(msg.into())
+    }
+
+    pub(crate) fn decode(e: impl Display) -> Self {
+        CommonError::Decode(e.to_string())
+    }
+}
+
+impl Display for CommonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommonError::Io(e) => write!(f, "io: {e}"),
+            CommonError::Crypto(e) => write!(f, "crypto: {e}"),
+            CommonError::Decode(e) => write!(f, "decode: {e}"),
+            CommonError::Frame(e) => write!(f, "frame: {e}"),
+            CommonError::Channel(e) => write!(f, "channel: {e}"),
+            CommonError::Protocol(e) => write!(f, "protocol: {e}"),
+            CommonError::Rejected { reason } => write!(f, "rejected: {reason}"),
+            CommonError::Rpc(e) => write!(f, "rpc: {e}"),
+            CommonError::KeyStore(e) => write!(f, "key store: {e}"),
+            CommonError::Platform(e) => write!(f, "platform: {e}"),
+        }
+    }
+}
+
+impl Error for CommonError {}
+
+impl From<std::io::Error> for CommonError {
+    fn from(value: std::io::Error) -> Self {
+        CommonError::Io(value)
+    }
+}
+
+impl From<FrameError> for CommonError {
+    fn from(value: FrameError) -> Self {
+        match value {
+            FrameError::Io(e) => CommonError::Io(e),
+            e => CommonError::Frame(e),
+        }
+    }
+}
+
+impl From<ChannelError> for CommonError {
+    fn from(value: ChannelError) -> Self {
+        CommonError::Channel(value)
+    }
+}
+
+impl From<RpcError> for CommonError {
+    fn from(value: RpcError) -> Self {
+        CommonError::Rpc(value)
+    }
+}
+
+impl From<k256::elliptic_curve::Error> for CommonError {
+    fn from(value: k256::elliptic_curve::Error) -> Self {
+        CommonError::Crypto(value.to_string())
+    }
+}
+
+impl From<k256::ecdsa::Error> for CommonError {
+    fn from(value: k256::ecdsa::Error) -> Self {
+        CommonError::Crypto(value.to_string())
+    }
+}
@@ -0,0 +1,47 @@
+use sha2::{Digest, Sha256};
+
+/// Words in a fingerprint, 96 bits so nobody can grind a key that reads the same.
+pub const FINGERPRINT_WORDS: usize = 12;
+
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adult", "agent", "alarm", "album", "alley", "amber", "angle",
+    "ankle", "apple", "apron", "arena", "armor", "arrow", "atlas", "attic", "autumn", "award",
+    "bacon", "badge", "bagel", "baker", "bamboo", "banjo", "barn", "basket", "beach", "beard",
+    "beaver", "bell", "bench", "berry", "bison", "blade", "blanket", "blossom", "boat", "bonnet",
+    "border", "bottle", "brain", "branch", "bread", "brick", "bridge", "broom", "bubble", "bucket",
+    "buffalo", "bugle", "bunny", "butter", "cabin", "cactus", "camel", "candle", "canoe", "canyon",
+    "carpet", "carrot", "castle", "cedar", "chalk", "cherry", "chess", "chimney", "cider",
+    "circus", "clock", "cloud", "clover", "cobra", "comet", "copper", "coral", "cotton", "cougar",
+    "crane", "crayon", "creek", "cricket", "crystal", "cup", "curtain", "dagger", "daisy",
+    "dancer", "delta", "desert", "diamond", "doctor", "dolphin", "donkey", "dragon", "drum",
+    "eagle", "earth", "easel", "echo", "elbow", "elder", "ember", "engine", "falcon", "feather",
+    "fence", "fern", "ferry", "fiddle", "finch", "flame", "flute", "forest", "fossil", "fountain",
+    "fox", "frost", "galaxy", "garden", "garlic", "gazelle", "geyser", "ginger", "glacier",
+    "globe", "goat", "gold", "gopher", "grape", "gravel", "guitar", "hammer", "harbor", "harp",
+    "hazel", "helmet", "heron", "hill", "honey", "hornet", "iceberg", "igloo", "island", "ivory",
+    "jacket", "jaguar", "jasmine", "jelly", "jewel", "jungle", "kayak", "kettle", "kiwi", "koala",
+    "ladder", "lagoon", "lamp", "lantern", "lava", "lemon", "lilac", "lion", "lizard", "llama",
+    "lobster", "locket", "lotus", "magnet", "mango", "maple", "marble", "meadow", "melon",
+    "meteor", "mirror", "mitten", "monkey", "moose", "mosaic", "moth", "muffin", "mushroom",
+    "needle", "nest", "noodle", "nutmeg", "oasis", "ocean", "olive", "onion", "orange", "orbit",
+    "orchid", "otter", "owl", "oyster", "paddle", "palace", "panda", "panther", "parrot", "peach",
+    "pebble", "pencil", "pepper", "piano", "pigeon", "pillow", "pilot", "pine", "planet", "plum",
+    "pony", "poppy", "pumpkin", "puzzle", "quail", "quartz", "quill", "rabbit", "radar", "raven",
+    "ribbon", "river", "robin", "rocket", "saddle", "salmon", "sandal", "satin", "shadow", "shark",
+    "shell", "silver", "spider", "sponge", "squid", "statue", "storm", "summit", "sunset", "swan",
+    "tiger", "timber", "tomato", "tulip", "turtle", "velvet", "violin", "walnut", "whale",
+    "willow", "wizard", "zebra",
+];
+
+/// A short word sequence for `pubkey` that people can read to each other to
+/// verify a key out of band, e.g.
+/// `otter-lamp-quartz-harp-willow-bread-comet-fern-kayak-lion-pebble-zebra`.
+pub fn fingerprint(pubkey: &[u8]) -> String {
+    let hash = Sha256::digest(pubkey);
+
+    hash[..FINGERPRINT_WORDS]
+        .iter()
+        .map(|b| WORDS[*b as usize])
+        .collect::<Vec<_>>()
+        .join("-")
+}
@@ -0,0 +1,81 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{Message, Outbox};
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    /// How often a [`Message::Ping`] is sent.
+    pub interval: Duration,
+    /// Silence after which the peer is considered gone.
+    pub idle_timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+/// Round trip measurement of one connection, shared by its reader and pinger.
+pub struct Liveness {
+    start: Instant,
+    rtt: AtomicU64,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Liveness {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            rtt: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    pub fn ping(&self) -> Message {
+        Message::Ping { sent: self.now() }
+    }
+
+    /// Records the round trip of an answered ping and returns it.
+    pub fn pong(&self, sent: u64) -> Duration {
+        let rtt = self.now().saturating_sub(sent);
+        self.rtt.store(rtt, Ordering::Relaxed);
+        Duration::from_micros(rtt)
+    }
+
+    /// Last measured round trip, `None` until the first pong arrived.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            rtt => Some(Duration::from_micros(rtt)),
+        }
+    }
+}
+
+/// Pings the peer every `interval` until the writer side goes away.
+pub async fn run_pinger(txch: Outbox, liveness: &Liveness, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        if txch.send(liveness.ping()).await.is_err() {
+            break;
+        }
+    }
+}
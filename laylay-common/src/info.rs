@@ -1,16 +1,18 @@
-use std::{error::Error, fmt::Display};
+use std::fmt::Display;
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use sysinfo::System;
 
-#[derive(Debug, BorshDeserialize, BorshSerialize)]
+use crate::CommonError;
+
+#[derive(Debug, Clone, BorshDeserialize, BorshSerialize)]
 pub struct Cpu {
     pub name: String,
     pub vendor_id: String,
     pub brand: String,
 }
 
-#[derive(Debug, BorshDeserialize, BorshSerialize)]
+#[derive(Debug, Clone, BorshDeserialize, BorshSerialize)]
 pub struct Info {
     pub name: Option<String>,
     pub host_name: Option<String>,
@@ -25,7 +27,12 @@ impl Info {
     // + https://developer.android.com/reference/android/os/Build
     //
     #[cfg(target_os = "android")]
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    pub fn new() -> Result<Self, CommonError> {
+        Self::from_jni().map_err(|e| CommonError::Platform(e.to_string()))
+    }
+
+    #[cfg(target_os = "android")]
+    fn from_jni() -> Result<Self, jni::errors::Error> {
         use jni::objects::JString;
 
         let ctx = ndk_context::android_context();
@@ -58,10 +65,13 @@ impl Info {
     }
 
     #[cfg(not(target_os = "android"))]
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    pub fn new() -> Result<Self, CommonError> {
         let sys = System::new_all();
 
-        let cpu = &sys.cpus()[0];
+        let cpu = sys
+            .cpus()
+            .first()
+            .ok_or_else(|| CommonError::Platform("no cpu reported".to_string()))?;
 
         Ok(Self {
             name: System::name(),
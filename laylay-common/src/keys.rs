@@ -0,0 +1,150 @@
+use bytes::Bytes;
+use hkdf::Hkdf;
+use k256::{
+    ecdsa::{
+        signature::{Signer, Verifier},
+        Signature, SigningKey, VerifyingKey,
+    },
+    PublicKey, SecretKey,
+};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::{CommonError, Direction, Opener, Sealer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    fn proof_label(&self) -> &'static [u8] {
+        match self {
+            Role::Client => b"laylay client proof",
+            Role::Server => b"laylay server proof",
+        }
+    }
+}
+
+/// Fresh random value each side puts into its greeting, so every connection
+/// derives its own keys even between the same two identities.
+pub fn random_nonce() -> Bytes {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    Bytes::copy_from_slice(&nonce)
+}
+
+/// The encoded greetings of both sides, hashed in a fixed order no matter
+/// which side sent first.
+#[derive(Default)]
+pub struct Transcript {
+    server: Vec<u8>,
+    client: Vec<u8>,
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, role: Role, greeting: Vec<u8>) {
+        match role {
+            Role::Client => self.client = greeting,
+            Role::Server => self.server = greeting,
+        }
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"laylay transcript");
+        hasher.update((self.server.len() as u32).to_be_bytes());
+        hasher.update(&self.server);
+        hasher.update((self.client.len() as u32).to_be_bytes());
+        hasher.update(&self.client);
+        hasher.finalize().into()
+    }
+
+    fn proof_message(&self, role: Role) -> Vec<u8> {
+        let mut msg = role.proof_label().to_vec();
+        msg.extend_from_slice(&self.hash());
+        msg
+    }
+
+    /// Signs the transcript to show we hold the private key of the pubkey we greeted with.
+    pub fn prove(&self, prikey: &SecretKey, role: Role) -> Bytes {
+        let signature: Signature = SigningKey::from(prikey).sign(&self.proof_message(role));
+        Bytes::copy_from_slice(&signature.to_bytes())
+    }
+
+    /// Checks a proof made with [`Transcript::prove`] by the side playing `role`.
+    pub fn verify(&self, pubkey: &Bytes, role: Role, proof: &Bytes) -> Result<(), CommonError> {
+        let key = VerifyingKey::from_sec1_bytes(pubkey)?;
+        let signature = Signature::from_slice(proof)?;
+        key.verify(&self.proof_message(role), &signature)?;
+
+        Ok(())
+    }
+}
+
+/// One key per direction, derived with HKDF-SHA256 from the ECDH secret and
+/// salted with the transcript hash.
+pub struct SessionKeys {
+    client_to_server: [u8; 32],
+    server_to_client: [u8; 32],
+}
+
+impl SessionKeys {
+    pub fn derive(
+        prikey: &SecretKey,
+        pubkey: &Bytes,
+        transcript: &Transcript,
+    ) -> Result<Self, CommonError> {
+        let pkey = PublicKey::from_sec1_bytes(pubkey)?;
+        let shared = k256::ecdh::diffie_hellman(prikey.to_nonzero_scalar(), pkey.as_affine());
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript.hash()), shared.raw_secret_bytes());
+
+        let mut client_to_server = [0u8; 32];
+        let mut server_to_client = [0u8; 32];
+        hkdf.expand(b"laylay c2s", &mut client_to_server)
+            .map_err(|e| CommonError::Crypto(e.to_string()))?;
+        hkdf.expand(b"laylay s2c", &mut server_to_client)
+            .map_err(|e| CommonError::Crypto(e.to_string()))?;
+
+        Ok(Self {
+            client_to_server,
+            server_to_client,
+        })
+    }
+
+    /// Independent keys for datagrams, so their sequence numbers never
+    /// produce a nonce already used on the stream.
+    pub fn datagram(&self) -> Self {
+        let derive = |key: &[u8; 32]| {
+            let mut next = [0u8; 32];
+            Hkdf::<Sha256>::new(None, key)
+                .expand(b"laylay datagram", &mut next)
+                .expect("32 bytes is a valid hkdf output length");
+            next
+        };
+
+        Self {
+            client_to_server: derive(&self.client_to_server),
+            server_to_client: derive(&self.server_to_client),
+        }
+    }
+
+    pub fn sealer(&self, role: Role) -> Sealer {
+        match role {
+            Role::Client => Sealer::new(&self.client_to_server, Direction::ClientToServer),
+            Role::Server => Sealer::new(&self.server_to_client, Direction::ServerToClient),
+        }
+    }
+
+    pub fn opener(&self, role: Role) -> Opener {
+        match role {
+            Role::Client => Opener::new(&self.server_to_client, Direction::ServerToClient),
+            Role::Server => Opener::new(&self.client_to_server, Direction::ClientToServer),
+        }
+    }
+}
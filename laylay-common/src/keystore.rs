@@ -0,0 +1,304 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use argon2::Argon2;
+use k256::SecretKey;
+use rand::{rngs::OsRng, RngCore};
+
+use crate::CommonError;
+
+/// Starts a key sealed with a passphrase, an unprotected key is just its 32 bytes.
+const MAGIC: &[u8; 4] = b"LLK1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Where the private key lives between runs.
+pub trait KeyStore: Send + Sync {
+    /// The stored key, `None` when nothing was stored yet.
+    fn load(&self) -> Result<Option<SecretKey>, CommonError>;
+
+    fn store(&self, key: &SecretKey) -> Result<(), CommonError>;
+
+    /// The stored key, a new one is generated and stored on first use.
+    fn load_or_create(&self) -> Result<SecretKey, CommonError> {
+        if let Some(key) = self.load()? {
+            return Ok(key);
+        }
+
+        let key = SecretKey::random(&mut OsRng);
+        self.store(&key)?;
+
+        Ok(key)
+    }
+}
+
+fn passphrase_cipher(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm, CommonError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| CommonError::KeyStore(e.to_string()))?;
+
+    Ok(Aes256Gcm::new(&key.into()))
+}
+
+/// Encodes `key` for storage or transfer, sealed with `passphrase` if there is one.
+pub fn export_key(key: &SecretKey, passphrase: Option<&str>) -> Result<Vec<u8>, CommonError> {
+    let raw = key.to_bytes();
+
+    let Some(passphrase) = passphrase else {
+        return Ok(raw.to_vec());
+    };
+
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+
+    let sealed = passphrase_cipher(passphrase, &salt)?
+        .encrypt(Nonce::from_slice(&nonce), raw.as_slice())
+        .map_err(|_| CommonError::KeyStore("could not seal key".to_string()))?;
+
+    let mut data = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + sealed.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&sealed);
+
+    Ok(data)
+}
+
+/// Decodes what [`export_key`] produced.
+pub fn import_key(data: &[u8], passphrase: Option<&str>) -> Result<SecretKey, CommonError> {
+    let Some(data) = data.strip_prefix(MAGIC) else {
+        return SecretKey::from_slice(data).map_err(|e| CommonError::KeyStore(e.to_string()));
+    };
+
+    let passphrase = passphrase
+        .ok_or_else(|| CommonError::KeyStore("key is protected by a passphrase".to_string()))?;
+
+    if data.len() < SALT_LEN + NONCE_LEN {
+        return Err(CommonError::KeyStore("truncated key".to_string()));
+    }
+
+    let (salt, data) = data.split_at(SALT_LEN);
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+
+    let raw = passphrase_cipher(passphrase, salt)?
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map_err(|_| CommonError::KeyStore("wrong passphrase or damaged key".to_string()))?;
+
+    SecretKey::from_slice(&raw).map_err(|e| CommonError::KeyStore(e.to_string()))
+}
+
+/// Limits the file to its owner, a no-op where there are no such permissions.
+fn restrict(filename: &Path) -> Result<(), CommonError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(filename, std::fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = filename;
+
+    Ok(())
+}
+
+/// Writes through a temporary file only the owner can read, then moves it in place.
+fn write_private(filename: &Path, data: &[u8]) -> Result<(), CommonError> {
+    let tmp = filename.with_extension("tmp");
+    let _ = std::fs::remove_file(&tmp);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, filename)?;
+
+    Ok(())
+}
+
+/// A key in a file that only its owner may read, sealed when a passphrase is given.
+///
+/// Unprotected keys written by older versions are sealed the first time they
+/// are loaded with a passphrase.
+pub struct FileKeyStore {
+    filename: PathBuf,
+    passphrase: Option<String>,
+}
+
+impl FileKeyStore {
+    pub fn new(filename: PathBuf, passphrase: Option<String>) -> Self {
+        Self {
+            filename,
+            passphrase,
+        }
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn load(&self) -> Result<Option<SecretKey>, CommonError> {
+        if !self.filename.exists() {
+            return Ok(None);
+        }
+
+        restrict(&self.filename)?;
+
+        let data = std::fs::read(&self.filename)?;
+        let key = import_key(&data, self.passphrase.as_deref()).map_err(|e| match e {
+            CommonError::KeyStore(e) => {
+                CommonError::KeyStore(format!("{}: {e}", self.filename.display()))
+            }
+            e => e,
+        })?;
+
+        if self.passphrase.is_some() && !data.starts_with(MAGIC) {
+            self.store(&key)?;
+        }
+
+        Ok(Some(key))
+    }
+
+    fn store(&self, key: &SecretKey) -> Result<(), CommonError> {
+        let data = export_key(key, self.passphrase.as_deref())?;
+
+        write_private(&self.filename, &data)
+    }
+}
+
+/// Keeps the key only for the lifetime of the process.
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    key: Mutex<Option<SecretKey>>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_key(key: SecretKey) -> Self {
+        Self {
+            key: Mutex::new(Some(key)),
+        }
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn load(&self) -> Result<Option<SecretKey>, CommonError> {
+        Ok(self.key.lock().unwrap().clone())
+    }
+
+    fn store(&self, key: &SecretKey) -> Result<(), CommonError> {
+        *self.key.lock().unwrap() = Some(key.clone());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file in the temp folder that is gone again after the test.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new() -> Self {
+            let name = format!("laylay-keystore-{}.bin", OsRng.next_u64());
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn export_import_roundtrip() {
+        let store = MemoryKeyStore::new();
+        let key = store.load_or_create().unwrap();
+        assert_eq!(store.load().unwrap(), Some(key.clone()));
+
+        let plain = export_key(&key, None).unwrap();
+        assert_eq!(plain.len(), 32);
+        assert_eq!(import_key(&plain, None).unwrap(), key);
+
+        let sealed = export_key(&key, Some("hunter2")).unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert_eq!(import_key(&sealed, Some("hunter2")).unwrap(), key);
+
+        let copy = MemoryKeyStore::with_key(import_key(&sealed, Some("hunter2")).unwrap());
+        assert_eq!(copy.load_or_create().unwrap(), key);
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let key = MemoryKeyStore::new().load_or_create().unwrap();
+        let sealed = export_key(&key, Some("hunter2")).unwrap();
+
+        assert!(matches!(
+            import_key(&sealed, Some("hunter3")),
+            Err(CommonError::KeyStore(_))
+        ));
+        assert!(matches!(
+            import_key(&sealed, None),
+            Err(CommonError::KeyStore(_))
+        ));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let key = MemoryKeyStore::new().load_or_create().unwrap();
+        let mut sealed = export_key(&key, Some("hunter2")).unwrap();
+        sealed[3] = b'9';
+
+        assert!(matches!(
+            import_key(&sealed, Some("hunter2")),
+            Err(CommonError::KeyStore(_))
+        ));
+        assert!(matches!(
+            import_key(MAGIC, Some("hunter2")),
+            Err(CommonError::KeyStore(_))
+        ));
+    }
+
+    #[test]
+    fn unsealed_key_gets_sealed() {
+        let file = TempFile::new();
+        let key = MemoryKeyStore::new().load_or_create().unwrap();
+        FileKeyStore::new(file.0.clone(), None).store(&key).unwrap();
+        assert_eq!(std::fs::read(&file.0).unwrap(), key.to_bytes().to_vec());
+
+        let store = FileKeyStore::new(file.0.clone(), Some("hunter2".to_string()));
+        assert_eq!(store.load().unwrap(), Some(key.clone()));
+
+        let data = std::fs::read(&file.0).unwrap();
+        assert!(data.starts_with(MAGIC));
+        assert_eq!(import_key(&data, Some("hunter2")).unwrap(), key);
+        assert_eq!(store.load().unwrap(), Some(key));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&file.0).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -1,108 +1,63 @@
-use std::{error::Error, path::PathBuf};
-
-use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
 use borsh::{BorshDeserialize, BorshSerialize};
 pub use bytes::Bytes;
 pub use k256::{PublicKey, SecretKey};
-use rand::{rngs::OsRng, RngCore};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
-};
 
+mod capabilities;
+pub use capabilities::Capabilities;
+mod channels;
+pub use channels::{Channel, Inbox, Outbox};
+mod cipher;
+pub use cipher::{ChannelError, Direction, Opener, Sealer};
+mod clock;
+pub use clock::{run_clock_sync, time_response, unix_micros, Clock, ClockConfig};
+mod codec;
+pub use codec::{FrameCodec, FrameError, DEFAULT_MAX_FRAME_SIZE};
+mod compression;
+pub use compression::{CompressionConfig, CompressionStats};
+mod connection;
+pub use connection::{Connection, ConnectionReader, ConnectionWriter, Peer};
+mod datagram;
+pub use datagram::{datagram_id, DatagramOpener, DatagramSealer, MAX_DATAGRAM_SIZE};
+mod errors;
+pub use errors::CommonError;
+mod fingerprint;
+pub use fingerprint::{fingerprint, FINGERPRINT_WORDS};
+mod heartbeat;
+pub use heartbeat::{run_pinger, HeartbeatConfig, Liveness};
 mod info;
 pub use info::Info;
+mod keys;
+pub use keys::{random_nonce, Role, SessionKeys, Transcript};
+mod keystore;
+pub use keystore::{export_key, import_key, FileKeyStore, KeyStore, MemoryKeyStore};
+mod negotiation;
+pub use negotiation::{Negotiated, Policy};
+mod rekey;
+pub use rekey::RekeyConfig;
+mod rpc;
+pub use rpc::{Request, Response, Rpc, RpcError, DEFAULT_RPC_TIMEOUT};
 mod version;
 pub use version::Version;
+mod websocket;
+pub use websocket::WebSocket;
 
-pub type AesCbcEnc = cbc::Encryptor<aes::Aes256>;
-pub type AesCbcDec = cbc::Decryptor<aes::Aes256>;
-
-pub fn get_private_key(folder: PathBuf) -> Result<SecretKey, Box<dyn Error>> {
-    let filename = folder.join("prikey.bin");
-
-    if filename.exists() {
-        let data = std::fs::read(&filename)?;
-        let key = SecretKey::from_bytes(data.as_slice().into())?;
-
-        Ok(key)
-    } else {
-        let key = SecretKey::random(&mut OsRng);
-        let data = key.to_bytes();
-
-        std::fs::write(&filename, data)?;
-
-        Ok(key)
-    }
-}
-
-pub fn shared_secret(pubkey: Bytes, prikey: &SecretKey) -> Vec<u8> {
-    let pkey = PublicKey::from_sec1_bytes(&pubkey).unwrap();
-    k256::ecdh::diffie_hellman(prikey.to_nonzero_scalar(), pkey.as_affine())
-        .raw_secret_bytes()
-        .to_vec()
-}
-
-pub async fn write_greeting(tx: &mut TcpStream, msg: &Message) -> Result<(), Box<dyn Error>> {
-    let data = borsh::to_vec(msg)?;
-
-    tx.write_u32(data.len() as u32).await?;
-    tx.write_all(&data).await?;
-
-    Ok(())
-}
-
-pub async fn write(
-    shared: &[u8],
-    tx: &mut OwnedWriteHalf,
-    msg: &Message,
-) -> Result<(), Box<dyn Error>> {
-    let data = borsh::to_vec(msg)?;
-
-    let mut iv = [0u8; 16];
-    OsRng.fill_bytes(&mut iv);
-
-    let enc = AesCbcEnc::new(shared.into(), &iv.into());
-    let encrypted = enc.encrypt_padded_vec_mut::<Pkcs7>(&data);
-
-    tx.write_u32(encrypted.len() as u32).await?;
-    tx.write(&iv).await?;
-    tx.write_all(&encrypted).await?;
-
-    Ok(())
-}
-
-pub async fn read_greeting(rx: &mut TcpStream) -> Result<Message, Box<dyn Error>> {
-    let size = rx.read_u32().await?;
-
-    let mut buffer = vec![0u8; size as usize];
-    rx.read_exact(&mut buffer).await?;
-
-    Ok(borsh::from_slice(&buffer)?)
-}
-
-pub async fn read(shared: &[u8], rx: &mut OwnedReadHalf) -> Result<Message, Box<dyn Error>> {
-    let size = rx.read_u32().await?;
-
-    let mut iv = [0u8; 16];
-    rx.read_exact(&mut iv).await?;
-
-    let mut buffer = vec![0u8; size as usize];
-    rx.read_exact(&mut buffer).await?;
-
-    let dec = AesCbcDec::new(shared.into(), &iv.into());
-    let data: Vec<u8> = dec.decrypt_padded_vec_mut::<Pkcs7>(&buffer)?;
-
-    Ok(borsh::from_slice(&data)?)
-}
+/// Wire protocol spoken by this build.
+///
+/// 1: AES-256-GCM sealed frames with per direction keys derived from the signed
+/// greeting transcript, length prefixed and announcing capabilities.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest wire protocol this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
 
 #[derive(BorshSerialize, BorshDeserialize)]
 pub enum Message {
+    /// Must stay the first variant with `protocol` leading, so a peer can still read
+    /// the version and refuse it with a reason when the rest no longer decodes.
     Greeting {
+        protocol: u32,
         pubkey: Bytes,
+        capabilities: Capabilities,
+        nonce: Bytes,
         version: Version,
         info: Info,
     },
@@ -111,10 +66,111 @@ pub enum Message {
         level: String,
         target: String,
     },
+    /// Needs [`Capabilities::LOBBIES`], answered with [`Message::LobbyJoined`] or [`Message::LobbyRejected`].
     JoinLobbby {
         name: String,
     },
     LeaveLobby {
         name: String,
     },
+    /// Signature over the transcript, see [`Transcript::prove`].
+    Proof {
+        signature: Bytes,
+    },
+    /// Carries the server's own [`Transcript::prove`] signature.
+    Accepted {
+        signature: Bytes,
+    },
+    /// Ends the handshake or, sent later, the connection. The reason is meant for humans.
+    Rejected {
+        reason: String,
+    },
+    /// Needs [`Capabilities::RPC`], answered with a [`Message::Response`] carrying the same id.
+    Request {
+        id: u64,
+        request: Request,
+    },
+    Response {
+        id: u64,
+        result: Result<Response, RpcError>,
+    },
+    /// Needs [`Capabilities::HEARTBEAT`], `sent` is opaque to the receiver and echoed in a [`Message::Pong`].
+    Ping {
+        sent: u64,
+    },
+    Pong {
+        sent: u64,
+    },
+    /// Needs [`Capabilities::RESUME`], the first message of the client after the handshake.
+    /// `token` comes from the [`Message::Session`] of an earlier connection.
+    Resume {
+        token: Option<Bytes>,
+    },
+    /// Answers [`Message::Resume`], `token` continues this session after a short drop.
+    Session {
+        token: Bytes,
+        resumed: bool,
+    },
+    /// Needs [`Capabilities::REKEY`], everything the sender seals after it uses the next key.
+    Rekey,
+    /// Needs [`Capabilities::DATAGRAM`], sent by the server after the handshake. Realtime
+    /// messages may go to `port` over UDP as datagrams starting with `id`.
+    Datagram {
+        port: u16,
+        id: u64,
+    },
+    /// Needs [`Capabilities::CLOCK`], `sent` is the client clock in microseconds since the
+    /// unix epoch, answered with a [`Message::TimeResponse`].
+    TimeRequest {
+        sent: u64,
+    },
+    /// `received` and `replied` are the server clock when the request came in and the answer went out.
+    TimeResponse {
+        sent: u64,
+        received: u64,
+        replied: u64,
+    },
+    /// Needs [`Capabilities::CLOCK`], a [`Message::Log`] stamped with the estimated server time.
+    TimedLog {
+        time: u64,
+        msg: String,
+        level: String,
+        target: String,
+    },
+    /// The lobby was joined, `members` are everyone in it including the joiner.
+    LobbyJoined {
+        name: String,
+        members: Vec<Bytes>,
+    },
+    LobbyRejected {
+        name: String,
+        reason: String,
+    },
+    /// Sent to the other members of a lobby when someone joins.
+    MemberJoined {
+        lobby: String,
+        pubkey: Bytes,
+    },
+    /// Sent to the remaining members of a lobby when someone leaves or disconnects.
+    MemberLeft {
+        lobby: String,
+        pubkey: Bytes,
+    },
+    /// Needs [`Capabilities::LOBBIES`], text for everyone in a lobby the sender is a member of.
+    Chat {
+        lobby: String,
+        text: String,
+    },
+    /// A chat message as relayed by the server, also replayed from the history after
+    /// [`Message::LobbyJoined`]. `time` is server time in microseconds since the unix epoch.
+    ChatMessage {
+        lobby: String,
+        from: Bytes,
+        text: String,
+        time: u64,
+    },
+    ChatRejected {
+        lobby: String,
+        reason: String,
+    },
 }
@@ -0,0 +1,140 @@
+use crate::{Capabilities, Version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// Outcome of the greeting exchange, both sides end up with the same values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub protocol: u32,
+    pub capabilities: Capabilities,
+}
+
+/// What one side demands of the other and offers in its greeting.
+#[derive(Clone)]
+pub struct Policy {
+    /// Oldest wire protocol the peer may speak.
+    pub min_protocol: u32,
+    /// Oldest application version the peer may run.
+    pub min_version: Option<Version>,
+    /// Features we are willing to use on this connection.
+    pub capabilities: Capabilities,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            min_protocol: MIN_PROTOCOL_VERSION,
+            min_version: None,
+            capabilities: Capabilities::supported(),
+        }
+    }
+}
+
+impl Policy {
+    /// The protocol both sides speak, the error is a reason meant for humans.
+    pub(crate) fn protocol(&self, protocol: u32) -> Result<u32, String> {
+        let min_protocol = self.min_protocol.max(MIN_PROTOCOL_VERSION);
+        let agreed = protocol.min(PROTOCOL_VERSION);
+
+        if agreed < min_protocol {
+            return Err(format!(
+                "protocol version {protocol} is not supported, at least {min_protocol} is required"
+            ));
+        }
+
+        Ok(agreed)
+    }
+
+    /// Checks the peer's greeting, the error is a reason meant for humans.
+    pub fn negotiate(
+        &self,
+        protocol: u32,
+        version: &Version,
+        capabilities: Capabilities,
+    ) -> Result<Negotiated, String> {
+        let agreed = self.protocol(protocol)?;
+
+        if let Some(min_version) = &self.min_version {
+            if min_version.higher(version) {
+                return Err(format!(
+                    "version {version} is too old, please update to {min_version} or newer"
+                ));
+            }
+        }
+
+        Ok(Negotiated {
+            protocol: agreed,
+            capabilities: self.capabilities.intersection(capabilities),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn agrees_on_the_older_protocol() {
+        let policy = Policy::default();
+        let v = version("1.0.0");
+
+        let negotiated = policy
+            .negotiate(PROTOCOL_VERSION + 5, &v, Capabilities::supported())
+            .unwrap();
+        assert_eq!(negotiated.protocol, PROTOCOL_VERSION);
+
+        assert!(policy
+            .negotiate(MIN_PROTOCOL_VERSION - 1, &v, Capabilities::supported())
+            .is_err());
+
+        let strict = Policy {
+            min_protocol: PROTOCOL_VERSION + 1,
+            ..Policy::default()
+        };
+        assert!(strict
+            .negotiate(PROTOCOL_VERSION, &v, Capabilities::supported())
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_old_versions() {
+        let policy = Policy {
+            min_version: Some(version("1.2.3")),
+            ..Policy::default()
+        };
+        let caps = Capabilities::supported();
+
+        assert!(policy
+            .negotiate(PROTOCOL_VERSION, &version("1.2.2"), caps)
+            .is_err());
+        assert!(policy
+            .negotiate(PROTOCOL_VERSION, &version("1.2.3"), caps)
+            .is_ok());
+        assert!(policy
+            .negotiate(PROTOCOL_VERSION, &version("2.0.0"), caps)
+            .is_ok());
+    }
+
+    #[test]
+    fn uses_shared_capabilities_only() {
+        let v = version("1.0.0");
+        let mut lobbies = Capabilities::empty();
+        lobbies.insert(Capabilities::LOBBIES);
+
+        let negotiated = Policy::default()
+            .negotiate(PROTOCOL_VERSION, &v, lobbies)
+            .unwrap();
+        assert_eq!(negotiated.capabilities, lobbies);
+
+        let policy = Policy {
+            capabilities: Capabilities::empty(),
+            ..Policy::default()
+        };
+        let negotiated = policy
+            .negotiate(PROTOCOL_VERSION, &v, Capabilities::supported())
+            .unwrap();
+        assert_eq!(negotiated.capabilities, Capabilities::empty());
+    }
+}
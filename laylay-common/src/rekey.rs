@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+/// When the sending side of a connection moves on to the next key,
+/// whichever limit is reached first.
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyConfig {
+    pub max_bytes: u64,
+    pub max_messages: u64,
+    pub max_age: Duration,
+}
+
+impl Default for RekeyConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1 << 30,
+            max_messages: 1 << 24,
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// How much the current sending key was used.
+pub(crate) struct KeyUsage {
+    config: RekeyConfig,
+    bytes: u64,
+    messages: u64,
+    since: Instant,
+}
+
+impl KeyUsage {
+    pub fn new(config: RekeyConfig) -> Self {
+        Self {
+            config,
+            bytes: 0,
+            messages: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Counts a sealed frame, returns `true` once the key is due.
+    pub fn record(&mut self, len: usize) -> bool {
+        self.bytes = self.bytes.saturating_add(len as u64);
+        self.messages += 1;
+
+        self.bytes >= self.config.max_bytes
+            || self.messages >= self.config.max_messages
+            || self.since.elapsed() >= self.config.max_age
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use bytes::Bytes;
+use tokio::sync::{oneshot, Mutex};
+
+use crate::{CommonError, Message, Outbox};
+
+/// How long [`Rpc::call`] waits when the caller has no better idea.
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, BorshSerialize, BorshDeserialize)]
+pub enum Request {
+    ListLobbies,
+    LobbyMembers { name: String },
+}
+
+#[derive(Debug, BorshSerialize, BorshDeserialize)]
+pub enum Response {
+    Lobbies {
+        names: Vec<String>,
+    },
+    /// Public keys in join order.
+    Members {
+        pubkeys: Vec<Bytes>,
+    },
+}
+
+#[derive(Debug, BorshSerialize, BorshDeserialize)]
+pub enum RpcError {
+    /// The other side does not know how to answer this request.
+    Unsupported,
+    /// The request was understood but could not be fulfilled.
+    Failed { reason: String },
+    /// No answer arrived in time.
+    Timeout,
+    /// The connection went away before the answer arrived.
+    Closed,
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Unsupported => write!(f, "unsupported request"),
+            RpcError::Failed { reason } => write!(f, "request failed: {reason}"),
+            RpcError::Timeout => write!(f, "request timed out"),
+            RpcError::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// Outstanding requests of one connection, matched to their responses by id.
+///
+/// The reader task hands every [`Message::Response`] to [`Rpc::complete`].
+pub struct Rpc {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Result<Response, RpcError>>>>,
+    txch: Outbox,
+}
+
+impl Rpc {
+    pub fn new(txch: Outbox) -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            txch,
+        }
+    }
+
+    pub async fn call(&self, request: Request, timeout: Duration) -> Result<Response, CommonError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(id, tx);
+
+        if self
+            .txch
+            .send(Message::Request { id, request })
+            .await
+            .is_err()
+        {
+            self.pending.lock().await.remove(&id);
+            return Err(RpcError::Closed.into());
+        }
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(result)) => Ok(result?),
+            Ok(Err(_)) => Err(RpcError::Closed.into()),
+            Err(_) => {
+                self.pending.lock().await.remove(&id);
+                Err(RpcError::Timeout.into())
+            }
+        }
+    }
+
+    /// Wakes up the caller waiting for `id`, returns `false` for unknown ids.
+    pub async fn complete(&self, id: u64, result: Result<Response, RpcError>) -> bool {
+        match self.pending.lock().await.remove(&id) {
+            Some(tx) => tx.send(result).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Fails every outstanding call, used when the connection is gone.
+    pub async fn close(&self) {
+        for (_, tx) in self.pending.lock().await.drain() {
+            let _ = tx.send(Err(RpcError::Closed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn response_completes_call() {
+        let (txch, mut inbox) = Outbox::new(4);
+        let rpc = Rpc::new(txch);
+
+        let responder = async {
+            let Some(Message::Request { id, .. }) = inbox.recv().await else {
+                panic!("expected a request");
+            };
+            let names = vec!["lobby".to_string()];
+            assert!(rpc.complete(id, Ok(Response::Lobbies { names })).await);
+        };
+        let (ret, _) = tokio::join!(
+            rpc.call(Request::ListLobbies, DEFAULT_RPC_TIMEOUT),
+            responder
+        );
+
+        assert!(matches!(ret, Ok(Response::Lobbies { names }) if names == ["lobby"]));
+    }
+
+    #[tokio::test]
+    async fn unanswered_call_times_out() {
+        let (txch, mut inbox) = Outbox::new(4);
+        let rpc = Rpc::new(txch);
+
+        let ret = rpc
+            .call(Request::ListLobbies, Duration::from_millis(10))
+            .await;
+        assert!(matches!(ret, Err(CommonError::Rpc(RpcError::Timeout))));
+
+        // A late answer finds nobody waiting.
+        let Some(Message::Request { id, .. }) = inbox.recv().await else {
+            panic!("expected a request");
+        };
+        assert!(!rpc.complete(id, Err(RpcError::Unsupported)).await);
+    }
+
+    #[tokio::test]
+    async fn close_fails_pending_calls() {
+        let (txch, _inbox) = Outbox::new(4);
+        let rpc = Rpc::new(txch);
+
+        let closer = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            rpc.close().await;
+        };
+        let (ret, _) = tokio::join!(rpc.call(Request::ListLobbies, DEFAULT_RPC_TIMEOUT), closer);
+
+        assert!(matches!(ret, Err(CommonError::Rpc(RpcError::Closed))));
+    }
+
+    #[tokio::test]
+    async fn call_without_writer_is_closed() {
+        let (txch, inbox) = Outbox::new(4);
+        drop(inbox);
+        let rpc = Rpc::new(txch);
+
+        let ret = rpc.call(Request::ListLobbies, DEFAULT_RPC_TIMEOUT).await;
+        assert!(matches!(ret, Err(CommonError::Rpc(RpcError::Closed))));
+    }
+}
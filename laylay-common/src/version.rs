@@ -1,7 +1,7 @@
 use borsh::{BorshDeserialize, BorshSerialize};
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-#[derive(BorshSerialize, BorshDeserialize)]
+#[derive(Clone, BorshSerialize, BorshDeserialize)]
 pub struct Version {
     pub major: u32,
     pub minor: u32,
@@ -39,3 +39,24 @@ impl Display for Version {
         )
     }
 }
+
+impl FromStr for Version {
+    type Err = String;
+
+    /// Parses `major.minor.patch`, the build details are left empty.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, '.').map(|p| p.parse::<u32>());
+
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) => Ok(Version {
+                major,
+                minor,
+                patch,
+                branch: String::new(),
+                commit: String::new(),
+                target: String::new(),
+            }),
+            _ => Err(format!("invalid version {s}, expected major.minor.patch")),
+        }
+    }
+}
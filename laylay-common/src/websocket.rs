@@ -0,0 +1,213 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, protocol::WebSocketConfig, Message as WsMessage},
+    WebSocketStream,
+};
+
+use crate::{codec::HEADER_SIZE, CommonError};
+
+/// Limits the WebSocket to messages that hold one frame of up to `max_frame_size`,
+/// so nothing larger is buffered before the frame codec gets to refuse it.
+fn config(max_frame_size: usize) -> (usize, WebSocketConfig) {
+    let max_message = max_frame_size + HEADER_SIZE;
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(max_message))
+        .max_frame_size(Some(max_message));
+
+    (max_message, config)
+}
+
+/// Byte stream over binary WebSocket messages, so a [`crate::Connection`] runs on
+/// top unchanged. Everything written between two flushes goes out as one
+/// message, which the framing layer makes exactly one frame.
+pub struct WebSocket<S> {
+    inner: WebSocketStream<S>,
+    /// Rest of the message being read.
+    read: Bytes,
+    write: Vec<u8>,
+    /// Largest message either side accepts, writes beyond it start the next one.
+    max_message: usize,
+}
+
+impl<S> WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Server side of the WebSocket upgrade, `max_frame_size` is the limit of the frame codec.
+    pub async fn accept(stream: S, max_frame_size: usize) -> Result<Self, CommonError> {
+        let (max_message, config) = config(max_frame_size);
+        let inner = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+            .await
+            .map_err(io::Error::other)?;
+
+        Ok(Self::new(inner, max_message))
+    }
+
+    fn new(inner: WebSocketStream<S>, max_message: usize) -> Self {
+        Self {
+            inner,
+            read: Bytes::new(),
+            write: Vec::new(),
+            max_message,
+        }
+    }
+
+    /// The transport below, for its addresses.
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+}
+
+impl WebSocket<TcpStream> {
+    /// Client side, `url` is a `ws://host:port/path` address.
+    pub async fn connect(url: &str, max_frame_size: usize) -> Result<Self, CommonError> {
+        let request = url.into_client_request().map_err(io::Error::other)?;
+        let uri = request.uri();
+        let host = uri
+            .host()
+            .ok_or_else(|| CommonError::protocol(format!("no host in {url}")))?;
+        let port = uri.port_u16().unwrap_or(80);
+
+        let stream = TcpStream::connect((host, port)).await?;
+        let (max_message, config) = config(max_frame_size);
+        let (inner, _) = tokio_tungstenite::client_async_with_config(request, stream, Some(config))
+            .await
+            .map_err(io::Error::other)?;
+
+        Ok(Self::new(inner, max_message))
+    }
+}
+
+impl<S> AsyncRead for WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read.is_empty() {
+            match ready!(self.inner.poll_next_unpin(cx)) {
+                Some(Ok(WsMessage::Binary(data))) => self.read = data,
+                // Pings are answered by the WebSocket itself, text is not ours.
+                Some(Ok(WsMessage::Ping(_) | WsMessage::Pong(_) | WsMessage::Text(_))) => {}
+                Some(Ok(WsMessage::Frame(_))) => {}
+                Some(Ok(WsMessage::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            }
+        }
+
+        let len = self.read.len().min(buf.remaining());
+        buf.put_slice(&self.read[..len]);
+        self.read.advance(len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // A full message goes out before more is taken, which also makes a slow
+        // peer hold up the writer instead of growing the buffer.
+        if self.write.len() >= self.max_message {
+            ready!(self.as_mut().poll_flush(cx))?;
+        }
+
+        let len = buf.len().min(self.max_message - self.write.len());
+        self.write.extend_from_slice(&buf[..len]);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write.is_empty() {
+            ready!(self.inner.poll_ready_unpin(cx)).map_err(io::Error::other)?;
+
+            let data = Bytes::from(std::mem::take(&mut self.write));
+            self.inner
+                .start_send_unpin(WsMessage::Binary(data))
+                .map_err(io::Error::other)?;
+        }
+
+        self.inner.poll_flush_unpin(cx).map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+
+        self.inner.poll_close_unpin(cx).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    /// Server side through [`WebSocket::accept`], client side with the given limits.
+    async fn pair(
+        max_frame_size: usize,
+        client: WebSocketConfig,
+    ) -> (WebSocket<DuplexStream>, WebSocketStream<DuplexStream>) {
+        let (client_io, server_io) = duplex(1 << 16);
+        let connect =
+            tokio_tungstenite::client_async_with_config("ws://test/", client_io, Some(client));
+        let (server, client) = tokio::join!(WebSocket::accept(server_io, max_frame_size), connect);
+
+        (server.unwrap(), client.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let (max_message, config) = config(1024);
+        let (mut server, client) = pair(1024, config).await;
+        let mut client = WebSocket::new(client, max_message);
+
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buffer = [0u8; 5];
+        server.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+
+        // More than one message holds goes out as several and reads back whole.
+        let data: Vec<u8> = (0..3 * max_message).map(|i| i as u8).collect();
+        server.write_all(&data).await.unwrap();
+        server.flush().await.unwrap();
+        let mut buffer = vec![0u8; data.len()];
+        client.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, data);
+    }
+
+    #[tokio::test]
+    async fn oversize_message_is_refused() {
+        let (mut server, mut client) = pair(64, WebSocketConfig::default()).await;
+
+        client
+            .send(WsMessage::Binary(Bytes::from(vec![0u8; 1024])))
+            .await
+            .unwrap();
+
+        let mut buffer = [0u8; 16];
+        assert!(server.read(&mut buffer).await.is_err());
+    }
+}
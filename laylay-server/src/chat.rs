@@ -0,0 +1,185 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use laylay_common::Bytes;
+use tokio::sync::Mutex;
+
+/// Longest chat message accepted, in characters.
+pub const MAX_CHAT_LEN: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChatConfig {
+    /// Messages replayed to someone joining a lobby.
+    pub history: usize,
+    /// Messages a user may send at once before the rate limit kicks in.
+    pub burst: u32,
+    /// Messages a user may send per minute in the long run.
+    pub per_minute: u32,
+}
+
+impl ChatConfig {
+    pub fn limiter(&self) -> RateLimiter {
+        RateLimiter::new(self.burst, self.interval())
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60) / self.per_minute.max(1)
+    }
+
+    /// How long an unused limiter takes to fill up again.
+    fn refill(&self) -> Duration {
+        self.interval() * self.burst.max(1)
+    }
+}
+
+/// Token bucket, allows `burst` at once and then one every `interval`.
+pub struct RateLimiter {
+    burst: f64,
+    interval: Duration,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, interval: Duration) -> Self {
+        Self {
+            burst: burst as f64,
+            interval,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() / self.interval.as_secs_f64();
+        self.tokens = (self.tokens + refill).min(self.burst);
+        self.last = now;
+    }
+
+    /// Whether a whole burst is available, then it is no different from a new one.
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    /// Takes a token, `false` when there is none left.
+    pub fn check(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Chat rate limits by key, so reconnecting does not hand out a fresh burst.
+pub struct ChatLimits {
+    config: ChatConfig,
+    limits: Mutex<Limits>,
+}
+
+struct Limits {
+    limiters: HashMap<Bytes, RateLimiter>,
+    /// When full limiters were last forgotten.
+    swept: Instant,
+}
+
+impl ChatLimits {
+    pub fn new(config: ChatConfig) -> Self {
+        Self {
+            config,
+            limits: Mutex::new(Limits {
+                limiters: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token of `pubkey`, `false` when it has none left.
+    pub async fn check(&self, pubkey: &Bytes) -> bool {
+        let mut limits = self.limits.lock().await;
+
+        // A full limiter is no different from a new one. Every key that did not chat
+        // for a whole refill has one, so sweeping that often only keeps recent keys.
+        if limits.swept.elapsed() >= self.config.refill() {
+            limits.limiters.retain(|_, limiter| !limiter.is_full());
+            limits.swept = Instant::now();
+        }
+
+        limits
+            .limiters
+            .entry(pubkey.clone())
+            .or_insert_with(|| self.config.limiter())
+            .check()
+    }
+
+    #[cfg(test)]
+    async fn len(&self) -> usize {
+        self.limits.lock().await.limiters.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_refill() {
+        let mut limiter = RateLimiter::new(3, Duration::from_millis(50));
+
+        assert!(limiter.check());
+        assert!(limiter.check());
+        assert!(limiter.check());
+        assert!(!limiter.check());
+        assert!(!limiter.is_full());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check());
+        assert!(!limiter.check());
+
+        std::thread::sleep(Duration::from_millis(160));
+        assert!(limiter.is_full());
+    }
+
+    fn config(burst: u32, per_minute: u32) -> ChatConfig {
+        ChatConfig {
+            history: 0,
+            burst,
+            per_minute,
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_are_per_key() {
+        let limits = ChatLimits::new(config(2, 1));
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+
+        assert!(limits.check(&a).await);
+        assert!(limits.check(&a).await);
+        assert!(!limits.check(&a).await);
+        assert!(limits.check(&b).await);
+        assert!(!limits.check(&a).await);
+    }
+
+    #[tokio::test]
+    async fn idle_keys_are_forgotten() {
+        // One token every 20ms, so a limiter is full again after 40ms.
+        let limits = ChatLimits::new(config(2, 3000));
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+
+        assert!(limits.check(&a).await);
+        assert!(limits.check(&b).await);
+        assert_eq!(limits.len().await, 2);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(limits.check(&b).await);
+        assert!(limits.check(&b).await);
+        assert_eq!(limits.len().await, 1);
+    }
+}
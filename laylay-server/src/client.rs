@@ -1,109 +1,645 @@
-use std::sync::Arc;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use laylay_common::{read_greeting, shared_secret, write_greeting, Bytes, Message, Version};
+use laylay_common::{
+    fingerprint, random_nonce, run_pinger, time_response, unix_micros, Bytes, Capabilities,
+    Channel, CommonError, CompressionStats, Connection, DatagramOpener, DatagramSealer, FrameCodec,
+    Liveness, Message, Negotiated, Outbox, Request, Response, Rpc, RpcError, Version,
+    MAX_DATAGRAM_SIZE,
+};
 use tokio::{
-    net::TcpStream,
-    sync::mpsc::{channel, Sender},
+    io::{AsyncRead, AsyncWrite},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Mutex, Notify,
+    },
 };
 
-use crate::{errors::ServerErrors, server::ServerContext};
+use crate::{
+    chat::MAX_CHAT_LEN,
+    errors::ServerErrors,
+    server::{DuplicateLogin, ServerContext},
+};
+
+/// Why a login is refused under [`DuplicateLogin::RejectNew`].
+const ALREADY_ONLINE: &str = "already logged in from another connection";
+/// Messages queued per channel for one client, room for the fan-out of a busy lobby.
+const OUTBOX_SIZE: usize = 256;
 
 pub struct Client {
     server: Arc<ServerContext>,
     pubkey: Bytes,
     version: Version,
+    negotiated: Negotiated,
     session_id: i64,
-    txch: Sender<Message>,
+    txch: Outbox,
+    pub rpc: Rpc,
+    liveness: Liveness,
+    compression: Arc<CompressionStats>,
+    /// Resumes the session after a drop, see [`ServerContext::park`].
+    token: Bytes,
+    /// Set once a new connection took over the session, see [`Client::hand_over`].
+    handed_over: AtomicBool,
+    datagram: Option<Datagram>,
+    /// Closes the connection, see [`Client::close`].
+    closed: Notify,
+    /// Last message before closing, written ahead of everything queued, see [`Client::kick`].
+    farewell: mpsc::Sender<Message>,
+}
+
+/// Realtime path of one client over the shared UDP socket, see [`crate::datagrams::Datagrams`].
+struct Datagram {
+    id: u64,
+    sealer: Mutex<DatagramSealer>,
+    opener: Mutex<DatagramOpener>,
+    /// Where the client was last heard from, `None` until its first datagram.
+    addr: Mutex<Option<SocketAddr>>,
 }
 
 impl Client {
-    pub async fn new(
-        ctx: Arc<ServerContext>,
-        mut stream: TcpStream,
-    ) -> Result<Arc<Self>, ServerErrors> {
-        write_greeting(&mut stream, &ctx.greeting).await?;
-
-        let msg = read_greeting(&mut stream).await?;
-
-        if let Message::Greeting {
-            pubkey,
-            version,
-            info,
-        } = msg
+    /// Serves one client on any transport, TCP or WebSocket.
+    pub async fn new<T>(ctx: Arc<ServerContext>, stream: T) -> Result<Arc<Self>, ServerErrors>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let codec = FrameCodec::new(ctx.config.max_frame_size);
+        let info = ctx.info.clone();
+        // Silent peers must not hold a task and a socket before authenticating.
+        let handshake = Connection::accept(stream, &ctx.prikey, info, codec, &ctx.config.policy);
+        let mut conn = tokio::time::timeout(ctx.config.heartbeat.idle_timeout, handshake)
+            .await
+            .map_err(|_| CommonError::protocol("no handshake from client"))??;
+        let compression = Arc::new(CompressionStats::default());
+        conn.set_rekey(ctx.config.rekey);
+        conn.set_compression(ctx.config.compression, compression.clone());
+        let peer = conn.peer().clone();
+
+        tracing::info!(
+            "greeting {} {}\nprotocol: {}\ncapabilities: {}\nversion: {}\ninfo: {}",
+            fingerprint(&peer.pubkey),
+            hex::encode(&peer.pubkey),
+            peer.negotiated.protocol,
+            peer.negotiated.capabilities,
+            peer.version,
+            peer.info
+        );
+
+        let resuming = peer.negotiated.capabilities.contains(Capabilities::RESUME);
+        let resume = if resuming {
+            Self::read_resume(&ctx, &mut conn).await?
+        } else {
+            None
+        };
+
+        // Checked early so a refused login does not take over a parked session,
+        // [`ServerContext::add_client`] checks again for logins racing this one.
+        if ctx.config.duplicate_login == DuplicateLogin::RejectNew
+            && ctx.is_duplicate(&peer.pubkey, resume.as_ref()).await
         {
-            tracing::info!(
-                "greeting {}\nversion: {}\ninfo: {}",
-                hex::encode(&pubkey),
-                version,
-                info
-            );
-            let session_id = ctx.db.get_session_id(&pubkey, &version, &info).await?;
-
-            let (mut rx, mut tx) = stream.into_split();
-            let (txch, mut rxch) = channel(10);
-            let client = Arc::new(Self {
-                server: ctx.clone(),
-                pubkey: pubkey.clone(),
-                version,
-                session_id,
-                txch,
-            });
-            let shared = shared_secret(pubkey.clone(), &ctx.prikey);
-
-            let shared0 = shared.clone();
+            let reason = ALREADY_ONLINE.to_string();
+            conn.send(&Message::Rejected {
+                reason: reason.clone(),
+            })
+            .await?;
+            return Err(CommonError::rejected(reason).into());
+        }
+
+        let resumed = match &resume {
+            Some(token) => ctx.resume(token, &peer.pubkey).await,
+            None => None,
+        };
+
+        let session_id = match resumed {
+            Some(session_id) => {
+                tracing::info!("{} resumed session {session_id}", fingerprint(&peer.pubkey));
+                session_id
+            }
+            None => {
+                ctx.db
+                    .get_session_id(&peer.pubkey, &peer.version, &peer.info)
+                    .await?
+            }
+        };
+
+        let token = random_nonce();
+        if resuming {
+            let msg = Message::Session {
+                token: token.clone(),
+                resumed: resumed.is_some(),
+            };
+            conn.send(&msg).await?;
+        }
+
+        let datagram = match &ctx.datagrams {
+            Some(datagrams) => {
+                let id = datagrams.next_id();
+
+                match conn.datagrams(id) {
+                    Some((sealer, opener)) => {
+                        let port = datagrams.port()?;
+                        conn.send(&Message::Datagram { port, id }).await?;
+
+                        Some(Datagram {
+                            id,
+                            sealer: Mutex::new(sealer),
+                            opener: Mutex::new(opener),
+                            addr: Mutex::new(None),
+                        })
+                    }
+                    None => None,
+                }
+            }
+            None => None,
+        };
+
+        let (mut tx, mut rx) = conn.split();
+        let (txch, mut rxch) = Outbox::new(OUTBOX_SIZE);
+        let (farewell, mut farewell_rx) = mpsc::channel(1);
+        let client = Arc::new(Self {
+            server: ctx.clone(),
+            pubkey: peer.pubkey,
+            version: peer.version,
+            negotiated: peer.negotiated,
+            session_id,
+            txch: txch.clone(),
+            rpc: Rpc::new(txch),
+            liveness: Liveness::new(),
+            compression,
+            token,
+            handed_over: AtomicBool::new(false),
+            datagram,
+            closed: Notify::new(),
+            farewell,
+        });
+
+        if !ctx.add_client(client.pubkey.clone(), client.clone()).await {
+            let reason = ALREADY_ONLINE.to_string();
+            tx.send(&Message::Rejected {
+                reason: reason.clone(),
+            })
+            .await?;
+            ctx.db.end_session(session_id).await?;
+            return Err(CommonError::rejected(reason).into());
+        }
+
+        if let (Some(datagrams), Some(datagram)) = (&ctx.datagrams, &client.datagram) {
+            datagrams.add_client(datagram.id, client.clone()).await;
+        }
+
+        let heartbeat = ctx.config.heartbeat;
+        let beating = client
+            .negotiated
+            .capabilities
+            .contains(Capabilities::HEARTBEAT);
+
+        let pinger = beating.then(|| {
             let cl0 = client.clone();
-            let ctx0 = ctx.clone();
             tokio::spawn(async move {
-                loop {
-                    let ret = laylay_common::read(&shared0, &mut rx)
-                        .await
-                        .map_err(|e| ServerErrors::from(e));
-
-                    match ret {
-                        Ok(msg) => {
-                            if let Err(e) = cl0.handle_message(msg).await {
-                                tracing::error!("{e}");
-                            }
-                        }
-                        Err(e) => {
+                run_pinger(cl0.txch.clone(), &cl0.liveness, heartbeat.interval).await;
+            })
+        });
+
+        let cl0 = client.clone();
+        let ctx0 = ctx.clone();
+        tokio::spawn(async move {
+            loop {
+                // `None` when the client stayed silent for too long.
+                let recv = async {
+                    if beating {
+                        tokio::time::timeout(heartbeat.idle_timeout, rx.recv())
+                            .await
+                            .ok()
+                    } else {
+                        Some(rx.recv().await)
+                    }
+                };
+
+                let ret = tokio::select! {
+                    ret = recv => ret,
+                    _ = cl0.closed.notified() => {
+                        tracing::info!("{} closed by the server", fingerprint(&cl0.pubkey));
+                        break;
+                    }
+                };
+
+                let ret = match ret {
+                    Some(ret) => ret.map_err(ServerErrors::from),
+                    None => {
+                        tracing::info!(
+                            "{} idle for {:?}",
+                            fingerprint(&cl0.pubkey),
+                            heartbeat.idle_timeout
+                        );
+                        break;
+                    }
+                };
+
+                match ret {
+                    Ok(msg) => {
+                        if let Err(e) = cl0.handle_message(msg).await {
                             tracing::error!("{e}");
-                            break;
                         }
                     }
+                    Err(e) => {
+                        tracing::error!("{e}");
+                        break;
+                    }
                 }
+            }
+
+            if let Some(pinger) = pinger {
+                pinger.abort();
+            }
+
+            cl0.rpc.close().await;
 
-                if let Err(e) = ctx0.db.end_session(session_id).await {
+            // A kicked client already left, its lobbies may be the new connection's by now.
+            if ctx0.remove_client(&cl0.pubkey, &cl0).await {
+                cl0.leave_lobbies().await;
+            }
+
+            if let (Some(datagrams), Some(datagram)) = (&ctx0.datagrams, &cl0.datagram) {
+                datagrams.remove_client(datagram.id).await;
+            }
+
+            let stats = &cl0.compression;
+            if stats.frames() > 0 {
+                tracing::info!(
+                    "{} compressed {} messages, saved {} of {} bytes",
+                    fingerprint(&cl0.pubkey),
+                    stats.frames(),
+                    stats.saved(),
+                    stats.original()
+                );
+            }
+
+            if cl0.handed_over.load(Ordering::Acquire) {
+                // The session goes on in the connection that resumed it.
+            } else if resuming {
+                ctx0.park(cl0.token.clone(), cl0.pubkey.clone(), session_id)
+                    .await;
+            } else if let Err(e) = ctx0.db.end_session(session_id).await {
+                tracing::error!("{e}");
+            }
+        });
+
+        tokio::spawn(async move {
+            loop {
+                let (msg, last) = tokio::select! {
+                    biased;
+                    Some(msg) = farewell_rx.recv() => (msg, true),
+                    Some(msg) = rxch.recv() => (msg, false),
+                    else => break,
+                };
+
+                if let Err(e) = tx.send(&msg).await {
                     tracing::error!("{e}");
+                    break;
                 }
-            });
-
-            tokio::spawn(async move {
-                while let Some(msg) = rxch.recv().await {
-                    let ret = laylay_common::write(&shared, &mut tx, &msg).await;
 
-                    if let Err(e) = ret {
-                        tracing::error!("{e}");
-                    }
+                if last {
+                    break;
                 }
-            });
+            }
+        });
 
-            ctx.add_client(client.pubkey.clone(), client.clone()).await;
+        Ok(client)
+    }
 
-            Ok(client)
-        } else {
-            Err(ServerErrors::internal("client did not send greeting"))
+    /// Reads the client's [`Message::Resume`], the token of the session it wants back if any.
+    async fn read_resume<T>(
+        ctx: &ServerContext,
+        conn: &mut Connection<T>,
+    ) -> Result<Option<Bytes>, ServerErrors>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let msg = tokio::time::timeout(ctx.config.heartbeat.idle_timeout, conn.recv())
+            .await
+            .map_err(|_| CommonError::protocol("no resume from client"))??;
+
+        match msg {
+            Message::Resume { token } => Ok(token),
+            _ => Err(CommonError::protocol("expected resume").into()),
         }
     }
 
     async fn handle_message(&self, msg: Message) -> Result<(), ServerErrors> {
+        let received = unix_micros();
+
         match msg {
             Message::Log { msg, target, level } => {
                 tracing::info!("{level} {target}: {msg}");
-                self.server.db.save_log(self.session_id, &level, &target, &msg).await?;
+                self.server
+                    .db
+                    .save_log(self.session_id, &level, &target, &msg, None)
+                    .await?;
+            }
+            Message::TimedLog {
+                time,
+                msg,
+                level,
+                target,
+            } => {
+                tracing::info!("{level} {target}: {msg}");
+                self.server
+                    .db
+                    .save_log(self.session_id, &level, &target, &msg, Some(time))
+                    .await?;
+            }
+            Message::Request { id, request } => {
+                let result = if self.negotiated.capabilities.contains(Capabilities::RPC) {
+                    self.handle_request(request).await
+                } else {
+                    Err(RpcError::Unsupported)
+                };
+
+                self.send(Message::Response { id, result }).await?;
+            }
+            Message::Response { id, result } => {
+                let known = self.rpc.complete(id, result).await;
+                if !known {
+                    tracing::warn!("response to unknown request {id}");
+                }
+            }
+            Message::Ping { sent } => {
+                self.send(Message::Pong { sent }).await?;
+            }
+            Message::Pong { sent } => {
+                let rtt = self.liveness.pong(sent);
+                self.server.db.save_rtt(self.session_id, rtt).await?;
+            }
+            Message::TimeRequest { sent } => {
+                self.send(time_response(sent, received)).await?;
+            }
+            Message::JoinLobbby { name } => {
+                self.join_lobby(name).await?;
+            }
+            Message::LeaveLobby { name } => {
+                self.leave_lobby(name).await;
+            }
+            Message::Chat { lobby, text } => {
+                self.chat(lobby, text).await?;
             }
             _ => {}
         }
 
         Ok(())
     }
+
+    async fn handle_request(&self, request: Request) -> Result<Response, RpcError> {
+        let lobbies = &self.server.lobbies;
+
+        match request {
+            Request::ListLobbies => Ok(Response::Lobbies {
+                names: lobbies.names().await,
+            }),
+            Request::LobbyMembers { name } => match lobbies.members(&name).await {
+                Some(pubkeys) => Ok(Response::Members { pubkeys }),
+                None => Err(RpcError::Failed {
+                    reason: format!("no lobby {name}"),
+                }),
+            },
+        }
+    }
+
+    async fn join_lobby(&self, name: String) -> Result<(), ServerErrors> {
+        if !self.negotiated.capabilities.contains(Capabilities::LOBBIES) {
+            let reason = "lobbies not negotiated".to_string();
+            return self.send(Message::LobbyRejected { name, reason }).await;
+        }
+
+        match self.server.lobbies.join(&name, &self.pubkey).await {
+            Ok(others) => {
+                tracing::info!("{} joined lobby {name}", fingerprint(&self.pubkey));
+
+                let mut members = others.clone();
+                members.push(self.pubkey.clone());
+                let msg = Message::LobbyJoined {
+                    name: name.clone(),
+                    members,
+                };
+                self.send(msg).await?;
+
+                // The join stands either way, a history that cannot be read must not undo it.
+                let limit = self.server.config.chat.history;
+                let history = match self.server.db.chat_history(&name, limit).await {
+                    Ok(history) => history,
+                    Err(e) => {
+                        tracing::error!("{e}");
+                        Vec::new()
+                    }
+                };
+
+                for (from, text, time) in history {
+                    let lobby = name.clone();
+                    self.send(Message::ChatMessage {
+                        lobby,
+                        from,
+                        text,
+                        time,
+                    })
+                    .await?;
+                }
+
+                self.server
+                    .broadcast(&others, || Message::MemberJoined {
+                        lobby: name.clone(),
+                        pubkey: self.pubkey.clone(),
+                    })
+                    .await;
+            }
+            Err(e) => {
+                let reason = e.to_string();
+                self.send(Message::LobbyRejected { name, reason }).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores the message and hands it to every member of the lobby, the sender included.
+    async fn chat(&self, lobby: String, text: String) -> Result<(), ServerErrors> {
+        let members = self
+            .server
+            .lobbies
+            .members(&lobby)
+            .await
+            .unwrap_or_default();
+
+        let rejected = if !members.contains(&self.pubkey) {
+            Some("not a member of the lobby")
+        } else if text.trim().is_empty() || text.chars().count() > MAX_CHAT_LEN {
+            Some("message empty or too long")
+        } else if !self.server.chat_limits.check(&self.pubkey).await {
+            Some("too many messages, slow down")
+        } else {
+            None
+        };
+
+        if let Some(reason) = rejected {
+            let reason = reason.to_string();
+            return self.send(Message::ChatRejected { lobby, reason }).await;
+        }
+
+        let time = unix_micros();
+        self.server
+            .db
+            .save_chat(self.session_id, &lobby, &text, time)
+            .await?;
+
+        self.server
+            .broadcast(&members, || Message::ChatMessage {
+                lobby: lobby.clone(),
+                from: self.pubkey.clone(),
+                text: text.clone(),
+                time,
+            })
+            .await;
+
+        Ok(())
+    }
+
+    async fn leave_lobby(&self, name: String) {
+        if let Some(others) = self.server.lobbies.leave(&name, &self.pubkey).await {
+            tracing::info!("{} left lobby {name}", fingerprint(&self.pubkey));
+            self.notify_left(name, &others).await;
+        }
+    }
+
+    pub fn session_id(&self) -> i64 {
+        self.session_id
+    }
+
+    /// Whether `token` resumes the session of this connection.
+    pub fn issued(&self, token: &Bytes) -> bool {
+        self.token == *token
+    }
+
+    /// Gives the session to a new connection resuming it with `token`, this one then
+    /// neither parks nor ends it. `None` when the token is not ours or it went already.
+    pub fn hand_over(&self, token: &Bytes) -> Option<i64> {
+        let handed_over = self.issued(token) && !self.handed_over.swap(true, Ordering::AcqRel);
+
+        handed_over.then_some(self.session_id)
+    }
+
+    /// Closes the connection without a reason, the client reconnects as after any drop.
+    pub fn close(&self) {
+        self.closed.notify_one();
+    }
+
+    /// Closes the connection because a newer one of the same key took over. The client
+    /// learns why from a [`Message::Rejected`] and should not reconnect on its own.
+    pub async fn kick(&self, reason: &str) {
+        self.close();
+
+        // The new login waits for this, so it must not wait on the old connection. A full
+        // queue does not matter, the reason goes out first on its own slot.
+        let msg = Message::Rejected {
+            reason: reason.to_string(),
+        };
+        if self.farewell.try_send(msg).is_err() {
+            tracing::debug!(
+                "no reason sent to {}, it is leaving already",
+                fingerprint(&self.pubkey)
+            );
+        }
+
+        self.leave_lobbies().await;
+    }
+
+    /// Takes the client out of every lobby, it is gone.
+    async fn leave_lobbies(&self) {
+        for (name, others) in self.server.lobbies.leave_all(&self.pubkey).await {
+            self.notify_left(name, &others).await;
+        }
+    }
+
+    async fn notify_left(&self, lobby: String, others: &[Bytes]) {
+        self.server
+            .broadcast(others, || Message::MemberLeft {
+                lobby: lobby.clone(),
+                pubkey: self.pubkey.clone(),
+            })
+            .await;
+    }
+
+    /// Opens a datagram routed to this client and handles the message inside.
+    pub async fn handle_datagram(&self, packet: &[u8], addr: SocketAddr) {
+        let Some(datagram) = &self.datagram else {
+            return;
+        };
+
+        let msg = match datagram.opener.lock().await.open(packet) {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::debug!("dropped datagram from {addr}: {e}");
+                return;
+            }
+        };
+
+        // Only authentic datagrams get here, so the client may roam between addresses.
+        *datagram.addr.lock().await = Some(addr);
+
+        // Runs on the receive loop of every client, so nothing here may wait on the
+        // database. Anything but realtime state has to come over the connection.
+        let ret = match msg {
+            // Answered the same way, which keeps NAT mappings open and proves the path works.
+            Message::Ping { sent } => self.send_realtime(Message::Pong { sent }).await,
+            Message::Pong { sent } => {
+                self.liveness.pong(sent);
+                Ok(())
+            }
+            _ => {
+                tracing::debug!("dropped datagram from {addr}: not realtime state");
+                Ok(())
+            }
+        };
+
+        if let Err(e) = ret {
+            tracing::error!("{e}");
+        }
+    }
+
+    pub async fn send(&self, msg: Message) -> Result<(), ServerErrors> {
+        self.txch
+            .send(msg)
+            .await
+            .map_err(|_| ServerErrors::internal("client writer is gone"))
+    }
+
+    /// Queues without waiting, fails when the client is too far behind or gone.
+    pub fn try_send(&self, msg: Message) -> Result<(), ServerErrors> {
+        self.txch.try_send(msg).map_err(|e| match e {
+            TrySendError::Full(_) => {
+                ServerErrors::internal(&format!("{} is not keeping up", fingerprint(&self.pubkey)))
+            }
+            TrySendError::Closed(_) => ServerErrors::internal("client writer is gone"),
+        })
+    }
+
+    /// Sends latest-value-wins state, as a datagram once the client was heard over UDP
+    /// and on the realtime channel of the connection otherwise.
+    pub async fn send_realtime(&self, msg: Message) -> Result<(), ServerErrors> {
+        if let (Some(datagrams), Some(datagram)) = (&self.server.datagrams, &self.datagram) {
+            if let Some(addr) = *datagram.addr.lock().await {
+                let packet = datagram.sealer.lock().await.seal(&msg)?;
+
+                if packet.len() <= MAX_DATAGRAM_SIZE {
+                    return datagrams.send_to(&packet, addr).await;
+                }
+            }
+        }
+
+        self.txch
+            .send_on(Channel::Realtime, msg)
+            .await
+            .map_err(|_| ServerErrors::internal("client writer is gone"))
+    }
 }
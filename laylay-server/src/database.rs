@@ -1,40 +1,122 @@
-use std::{error::Error, path::PathBuf};
+use std::{
+    error::Error,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
 
 use laylay_common::{Bytes, Info, Version};
-use rusqlite::{Connection, OptionalExtension};
-use tokio::sync::Mutex;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use tokio::sync::{mpsc, oneshot};
 
-use crate::errors::ServerErrors;
+use crate::{errors::ServerErrors, migrations};
 
+/// Queries waiting for the database thread before callers have to wait.
+const QUEUE_SIZE: usize = 1024;
+
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+/// The connection lives on its own thread and queries are sent to it, so a
+/// slow write blocks that thread instead of a runtime worker.
 pub struct Database {
-    conn: Mutex<Connection>,
+    jobs: mpsc::Sender<Job>,
 }
 
 impl Database {
+    /// Opens the database and brings its schema up to date.
     pub fn new(folder: PathBuf) -> Result<Self, Box<dyn Error>> {
-        let filename = folder.join("laylay.db");
-        let existed = filename.exists();
-        let conn = Connection::open(filename)?;
+        let mut conn = Connection::open(folder.join("laylay.db"))?;
+        // Before anything is written, a database of a newer server stays as it is.
+        migrations::check(&conn)?;
+        // Log writes no longer wait for a full sync each, readers never wait for writers.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        migrations::migrate(&mut conn)?;
+
+        Self::spawn(conn)
+    }
 
-        if !existed {
-            conn.execute_batch(include_str!("schema.sql"))?;
+    /// Opens the database without changing anything, its schema has to be up to date.
+    pub fn read_only(folder: PathBuf) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open_with_flags(
+            folder.join("laylay.db"),
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?;
+
+        let version = migrations::check(&conn)?;
+        if version < migrations::SCHEMA_VERSION {
+            return Err(format!(
+                "database schema version {version} is outdated, run --migrate-only first"
+            )
+            .into());
         }
 
-        Ok(Self {
-            conn: Mutex::new(conn),
-        })
+        Self::spawn(conn)
     }
 
-    pub async fn save_log(&self, session_id: i64, lvl: &str, target: &str, msg: &str) -> Result<(), ServerErrors> {
+    fn spawn(mut conn: Connection) -> Result<Self, Box<dyn Error>> {
+        let (jobs, mut rx) = mpsc::channel::<Job>(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("database".to_string())
+            .spawn(move || {
+                while let Some(job) = rx.blocking_recv() {
+                    // One bad query must not take every later one down with it, whatever
+                    // it left open is rolled back as it unwinds.
+                    if panic::catch_unwind(AssertUnwindSafe(|| job(&mut conn))).is_err() {
+                        tracing::error!("database query panicked");
+                    }
+                }
+            })?;
+
+        Ok(Self { jobs })
+    }
+
+    /// Runs `f` on the database thread and waits for its result.
+    async fn call<T, F>(&self, f: F) -> Result<T, ServerErrors>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, ServerErrors> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |conn| {
+            let _ = tx.send(f(conn));
+        });
+
+        self.jobs
+            .send(job)
+            .await
+            .map_err(|_| ServerErrors::internal("database thread is gone"))?;
+        rx.await
+            .map_err(|_| ServerErrors::internal("database query panicked"))?
+    }
+
+    /// `time` is when the client logged it in server time, microseconds since the unix epoch.
+    /// Without it the time of arrival is used.
+    pub async fn save_log(
+        &self,
+        session_id: i64,
+        lvl: &str,
+        target: &str,
+        msg: &str,
+        time: Option<u64>,
+    ) -> Result<(), ServerErrors> {
         let sql = r#"
-        INSERT INTO logs(session_id, level_id, target, message) 
-        VALUES(?, (SELECT id FROM log_level WHERE name = ?), ?, ?)
+        INSERT INTO logs(session_id, level_id, target, message, logged) 
+        VALUES(?, (SELECT id FROM log_level WHERE name = ?), ?, ?, COALESCE(
+            strftime('%Y-%m-%d %H:%M:%f', ? / 1000000.0, 'unixepoch'),
+            strftime('%Y-%m-%d %H:%M:%f')
+        ))
         "#;
-        let conn = self.conn.lock().await;
-        let mut stmnt = conn.prepare_cached(sql)?;
-        stmnt.execute((session_id, lvl, target, msg))?;
+        let time = time.map(|t| t as i64);
+        let (lvl, target, msg) = (lvl.to_string(), target.to_string(), msg.to_string());
+        self.call(move |conn| {
+            let mut stmnt = conn.prepare_cached(sql)?;
+            stmnt.execute((session_id, lvl, target, msg, time))?;
 
-        Ok(())
+            Ok(())
+        })
+        .await
     }
 
     pub async fn get_session_id(
@@ -44,6 +126,7 @@ impl Database {
         info: &Info,
     ) -> Result<i64, ServerErrors> {
         let pubhex = hex::encode(pubkey);
+        let (version, info) = (version.clone(), info.clone());
 
         let select_user_sql = r#"SELECT id FROM user WHERE pubkey = ?"#;
         let insert_user_sql = r#"
@@ -97,87 +180,207 @@ impl Database {
             VALUES(?, datetime())
             RETURNING id
         "#;
-        let conn = self.conn.lock().await;
-        let mut user_stmnt = conn.prepare_cached(select_user_sql)?;
-        let mut version_stmnt = conn.prepare_cached(select_version_sql)?;
-        let mut info_stmnt = conn.prepare_cached(select_info_sql)?;
-        let mut uvs_stmnt = conn.prepare_cached(select_uvs_sql)?;
-        let mut session_stmnt = conn.prepare_cached(session_sql)?;
-
-        let user_id: Option<i64> = user_stmnt
-            .query_row((&pubhex,), |r| r.get(0))
-            .optional()
-            .map_err(|e| ServerErrors::db(e, "get user id"))?;
-        let user_id: i64 = if let Some(user_id) = user_id {
-            user_id
-        } else {
-            let mut stmnt = conn.prepare_cached(&insert_user_sql)?;
-            stmnt.query_row((pubhex,), |r| r.get(0))?
-        };
-
-        let version_params = (
-            &version.major,
-            &version.minor,
-            &version.patch,
-            &version.target,
-        );
-        let version_id: Option<i64> = version_stmnt
-            .query_row(version_params, |r| r.get(0))
-            .optional()
-            .map_err(|e| ServerErrors::db(e, "get version"))?;
-        let version_id: i64 = if let Some(version_id) = version_id {
-            version_id
-        } else {
-            let mut stmnt = conn.prepare_cached(&insert_version_sql)?;
-            stmnt.query_row(version_params, |r| r.get(0))?
-        };
-
-        let info_params = (
-            &info.name,
-            &info.host_name,
-            &info.kernel_version,
-            &info.os_version,
-            &info.cpu.name,
-            &info.cpu.vendor_id,
-            &info.cpu.brand,
-            &info.memory,
-        );
-        let info_id: Option<i64> = info_stmnt
-            .query_row(info_params, |r| r.get(0))
-            .optional()
-            .map_err(|e| ServerErrors::db(e, "get info"))?;
-        let info_id: i64 = if let Some(id) = info_id {
-            id
-        } else {
-            let mut stmnt = conn.prepare_cached(&insert_info_sql)?;
-            stmnt.query_row(info_params, |r| r.get(0))?
-        };
-
-        let uvs_params = (user_id, version_id, info_id);
-        let uvs_id: Option<i64> = uvs_stmnt
-            .query_row(uvs_params, |r| r.get(0))
-            .optional()
-            .map_err(|e| ServerErrors::db(e, "get use version info id"))?;
-        let uvs_id: i64 = if let Some(id) = uvs_id {
-            id
-        } else {
-            let mut stmnt = conn.prepare_cached(&insert_uvs_sql)?;
-            stmnt.query_row(uvs_params, |r| r.get(0))?
-        };
-
-        let session_id: i64 = session_stmnt.query_row((uvs_id,), |r| r.get(0))?;
-
-        Ok(session_id)
+        self.call(move |conn| {
+            let mut user_stmnt = conn.prepare_cached(select_user_sql)?;
+            let mut version_stmnt = conn.prepare_cached(select_version_sql)?;
+            let mut info_stmnt = conn.prepare_cached(select_info_sql)?;
+            let mut uvs_stmnt = conn.prepare_cached(select_uvs_sql)?;
+            let mut session_stmnt = conn.prepare_cached(session_sql)?;
+
+            let user_id: Option<i64> = user_stmnt
+                .query_row((&pubhex,), |r| r.get(0))
+                .optional()
+                .map_err(|e| ServerErrors::db(e, "get user id"))?;
+            let user_id: i64 = if let Some(user_id) = user_id {
+                user_id
+            } else {
+                let mut stmnt = conn.prepare_cached(insert_user_sql)?;
+                stmnt.query_row((pubhex,), |r| r.get(0))?
+            };
+
+            let version_params = (
+                &version.major,
+                &version.minor,
+                &version.patch,
+                &version.target,
+            );
+            let version_id: Option<i64> = version_stmnt
+                .query_row(version_params, |r| r.get(0))
+                .optional()
+                .map_err(|e| ServerErrors::db(e, "get version"))?;
+            let version_id: i64 = if let Some(version_id) = version_id {
+                version_id
+            } else {
+                let mut stmnt = conn.prepare_cached(insert_version_sql)?;
+                stmnt.query_row(version_params, |r| r.get(0))?
+            };
+
+            let info_params = (
+                &info.name,
+                &info.host_name,
+                &info.kernel_version,
+                &info.os_version,
+                &info.cpu.name,
+                &info.cpu.vendor_id,
+                &info.cpu.brand,
+                &info.memory,
+            );
+            let info_id: Option<i64> = info_stmnt
+                .query_row(info_params, |r| r.get(0))
+                .optional()
+                .map_err(|e| ServerErrors::db(e, "get info"))?;
+            let info_id: i64 = if let Some(id) = info_id {
+                id
+            } else {
+                let mut stmnt = conn.prepare_cached(insert_info_sql)?;
+                stmnt.query_row(info_params, |r| r.get(0))?
+            };
+
+            let uvs_params = (user_id, version_id, info_id);
+            let uvs_id: Option<i64> = uvs_stmnt
+                .query_row(uvs_params, |r| r.get(0))
+                .optional()
+                .map_err(|e| ServerErrors::db(e, "get use version info id"))?;
+            let uvs_id: i64 = if let Some(id) = uvs_id {
+                id
+            } else {
+                let mut stmnt = conn.prepare_cached(insert_uvs_sql)?;
+                stmnt.query_row(uvs_params, |r| r.get(0))?
+            };
+
+            let session_id: i64 = session_stmnt.query_row((uvs_id,), |r| r.get(0))?;
+
+            Ok(session_id)
+        })
+        .await
     }
 
     pub async fn end_session(&self, session_id: i64) -> Result<(), ServerErrors> {
         let sql = r#"
             UPDATE user_session SET ended = datetime() WHERE id = ?
         "#;
-        let conn = self.conn.lock().await;
-        let mut stmnt = conn.prepare_cached(sql)?;
-        stmnt.execute((session_id,))?;
+        self.call(move |conn| {
+            let mut stmnt = conn.prepare_cached(sql)?;
+            stmnt.execute((session_id,))?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Keeps the latest measured round trip of the session.
+    pub async fn save_rtt(&self, session_id: i64, rtt: Duration) -> Result<(), ServerErrors> {
+        let sql = r#"
+            UPDATE user_session SET rtt_ms = ? WHERE id = ?
+        "#;
+        self.call(move |conn| {
+            let mut stmnt = conn.prepare_cached(sql)?;
+            stmnt.execute((rtt.as_secs_f64() * 1000.0, session_id))?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// `time` is server time in microseconds since the unix epoch.
+    pub async fn save_chat(
+        &self,
+        session_id: i64,
+        lobby: &str,
+        msg: &str,
+        time: u64,
+    ) -> Result<(), ServerErrors> {
+        let sql = r#"
+            INSERT INTO chat(session_id, lobby, message, sent)
+            VALUES(?, ?, ?, strftime('%Y-%m-%d %H:%M:%f', ? / 1000000.0, 'unixepoch'))
+        "#;
+        let (lobby, msg) = (lobby.to_string(), msg.to_string());
+        self.call(move |conn| {
+            let mut stmnt = conn.prepare_cached(sql)?;
+            stmnt.execute((session_id, lobby, msg, time as i64))?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// The last `limit` messages of the lobby, oldest first, as sender, message and time.
+    pub async fn chat_history(
+        &self,
+        lobby: &str,
+        limit: usize,
+    ) -> Result<Vec<(Bytes, String, u64)>, ServerErrors> {
+        let sql = r#"
+            SELECT u.pubkey, c.message,
+                CAST(ROUND((julianday(c.sent) - 2440587.5) * 86400000) AS INTEGER) * 1000
+            FROM chat c
+            JOIN user_session s ON s.id = c.session_id
+            JOIN user_version_sys uvs ON uvs.id = s.uvs_id
+            JOIN user u ON u.id = uvs.user_id
+            WHERE c.lobby = ?
+            ORDER BY c.id DESC
+            LIMIT ?
+        "#;
+        let lobby = lobby.to_string();
+        self.call(move |conn| {
+            let mut stmnt = conn.prepare_cached(sql)?;
+            let rows = stmnt.query_map((lobby, limit as i64), |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, i64>(2)?,
+                ))
+            })?;
+
+            let mut history = Vec::new();
+            for row in rows {
+                let (pubkey, msg, time) = row?;
+                let pubkey =
+                    hex::decode(pubkey).map_err(|e| ServerErrors::internal(&e.to_string()))?;
+                history.push((Bytes::from(pubkey), msg, time as u64));
+            }
+            history.reverse();
+
+            Ok(history)
+        })
+        .await
+    }
+
+    /// Public keys of everybody who ever connected.
+    pub async fn users(&self) -> Result<Vec<Bytes>, ServerErrors> {
+        let sql = r#"SELECT pubkey FROM user ORDER BY id"#;
+        self.call(move |conn| {
+            let mut stmnt = conn.prepare_cached(sql)?;
+            let rows = stmnt.query_map((), |r| r.get::<_, String>(0))?;
+
+            let mut users = Vec::new();
+            for row in rows {
+                let pubkey =
+                    hex::decode(row?).map_err(|e| ServerErrors::internal(&e.to_string()))?;
+                users.push(Bytes::from(pubkey));
+            }
+
+            Ok(users)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn survives_a_panicking_query() {
+        let db = Database::spawn(Connection::open_in_memory().unwrap()).unwrap();
+
+        let ret: Result<(), _> = db.call(|_| panic!("bad row")).await;
+        assert!(ret.is_err());
 
-        Ok(())
+        let one: i64 = db
+            .call(|conn| Ok(conn.query_row("SELECT 1", (), |r| r.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(one, 1);
     }
 }
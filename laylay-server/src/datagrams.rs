@@ -0,0 +1,73 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use laylay_common::{datagram_id, MAX_DATAGRAM_SIZE};
+use tokio::{net::UdpSocket, sync::RwLock};
+
+use crate::{client::Client, errors::ServerErrors};
+
+/// The UDP socket the realtime datagrams of all clients arrive on, told apart
+/// by the id each client got with its [`laylay_common::Message::Datagram`].
+pub struct Datagrams {
+    socket: UdpSocket,
+    next_id: AtomicU64,
+    clients: RwLock<HashMap<u64, Arc<Client>>>,
+}
+
+impl Datagrams {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            next_id: AtomicU64::new(1),
+            clients: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn port(&self) -> Result<u16, ServerErrors> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub async fn add_client(&self, id: u64, client: Arc<Client>) {
+        self.clients.write().await.insert(id, client);
+    }
+
+    pub async fn remove_client(&self, id: u64) {
+        self.clients.write().await.remove(&id);
+    }
+
+    pub async fn send_to(&self, packet: &[u8], addr: SocketAddr) -> Result<(), ServerErrors> {
+        self.socket.send_to(packet, addr).await?;
+
+        Ok(())
+    }
+
+    /// Hands every datagram to the client it belongs to until the socket fails.
+    pub async fn run(&self) -> Result<(), ServerErrors> {
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buffer).await?;
+            let packet = &buffer[..len];
+
+            let client = match datagram_id(packet) {
+                Some(id) => self.clients.read().await.get(&id).cloned(),
+                None => None,
+            };
+
+            match client {
+                Some(client) => client.handle_datagram(packet, addr).await,
+                None => tracing::debug!("datagram from unknown {addr}"),
+            }
+        }
+    }
+}
@@ -1,10 +1,17 @@
 use std::{backtrace::Backtrace, fmt::Display};
 
+use laylay_common::CommonError;
+
 #[derive(Debug)]
 pub enum ServerErrorKind {
     Io,
     Internal,
     Db,
+    Rejected,
+    Protocol,
+    Crypto,
+    Rpc,
+    KeyStore,
 }
 
 #[derive(Debug)]
@@ -15,14 +22,22 @@ pub struct ServerErrors {
 }
 
 impl ServerErrors {
-    pub fn internal(msg: &str) ->Self {
+    pub fn internal(msg: &str) -> Self {
         Self {
             msg: msg.to_string(),
             kind: ServerErrorKind::Internal,
             backtrace: Backtrace::capture(),
         }
     }
-    
+
+    pub fn rejected(msg: &str) -> Self {
+        Self {
+            msg: msg.to_string(),
+            kind: ServerErrorKind::Rejected,
+            backtrace: Backtrace::capture(),
+        }
+    }
+
     pub fn db(e: rusqlite::Error, msg: &str) -> Self {
         Self {
             msg: format!("{msg} -> {e}"),
@@ -59,6 +74,28 @@ impl From<Box<dyn std::error::Error>> for ServerErrors {
     }
 }
 
+impl From<CommonError> for ServerErrors {
+    fn from(value: CommonError) -> Self {
+        let kind = match &value {
+            CommonError::Io(_) => ServerErrorKind::Io,
+            CommonError::Crypto(_) | CommonError::Channel(_) => ServerErrorKind::Crypto,
+            CommonError::Decode(_) | CommonError::Frame(_) | CommonError::Protocol(_) => {
+                ServerErrorKind::Protocol
+            }
+            CommonError::Rejected { reason } => return Self::rejected(reason),
+            CommonError::Rpc(_) => ServerErrorKind::Rpc,
+            CommonError::KeyStore(_) => ServerErrorKind::KeyStore,
+            CommonError::Platform(_) => ServerErrorKind::Internal,
+        };
+
+        Self {
+            kind,
+            msg: value.to_string(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+}
+
 impl From<rusqlite::Error> for ServerErrors {
     fn from(value: rusqlite::Error) -> Self {
         Self {
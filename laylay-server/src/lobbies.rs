@@ -0,0 +1,183 @@
+use std::{collections::HashMap, fmt::Display};
+
+use laylay_common::Bytes;
+use tokio::sync::RwLock;
+
+/// Longest lobby name accepted, in characters.
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug)]
+pub enum LobbyError {
+    InvalidName,
+    /// The lobby already has the maximum number of members.
+    Full {
+        max: usize,
+    },
+}
+
+impl Display for LobbyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LobbyError::InvalidName => write!(f, "invalid lobby name"),
+            LobbyError::Full { max } => write!(f, "lobby is full ({max} members)"),
+        }
+    }
+}
+
+impl std::error::Error for LobbyError {}
+
+/// Lobbies by name with their members in join order. A lobby exists as long
+/// as it has members, it is created by the first join.
+pub struct Lobbies {
+    lobbies: RwLock<HashMap<String, Vec<Bytes>>>,
+    max_members: usize,
+}
+
+impl Lobbies {
+    pub fn new(max_members: usize) -> Self {
+        Self {
+            lobbies: RwLock::new(HashMap::new()),
+            max_members,
+        }
+    }
+
+    /// Adds `pubkey` to the lobby and returns the members that were there before.
+    pub async fn join(&self, name: &str, pubkey: &Bytes) -> Result<Vec<Bytes>, LobbyError> {
+        if name.trim().is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(LobbyError::InvalidName);
+        }
+
+        let mut lobbies = self.lobbies.write().await;
+        let members = lobbies.entry(name.to_string()).or_default();
+
+        let others: Vec<Bytes> = members.iter().filter(|m| *m != pubkey).cloned().collect();
+
+        if others.len() == members.len() {
+            if members.len() >= self.max_members {
+                return Err(LobbyError::Full {
+                    max: self.max_members,
+                });
+            }
+
+            members.push(pubkey.clone());
+        }
+
+        Ok(others)
+    }
+
+    /// Removes `pubkey` from the lobby and returns the members left behind,
+    /// `None` when it was not a member. The last one out closes the lobby.
+    pub async fn leave(&self, name: &str, pubkey: &Bytes) -> Option<Vec<Bytes>> {
+        let mut lobbies = self.lobbies.write().await;
+        let members = lobbies.get_mut(name)?;
+
+        let index = members.iter().position(|m| m == pubkey)?;
+        members.remove(index);
+        let others = members.clone();
+
+        if others.is_empty() {
+            lobbies.remove(name);
+        }
+
+        Some(others)
+    }
+
+    /// Removes `pubkey` from every lobby, returns each left lobby with the members left behind.
+    pub async fn leave_all(&self, pubkey: &Bytes) -> Vec<(String, Vec<Bytes>)> {
+        let mut lobbies = self.lobbies.write().await;
+        let mut left = Vec::new();
+
+        for (name, members) in lobbies.iter_mut() {
+            if let Some(index) = members.iter().position(|m| m == pubkey) {
+                members.remove(index);
+                left.push((name.clone(), members.clone()));
+            }
+        }
+
+        lobbies.retain(|_, members| !members.is_empty());
+
+        left
+    }
+
+    pub async fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.lobbies.read().await.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub async fn members(&self, name: &str) -> Option<Vec<Bytes>> {
+        self.lobbies.read().await.get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u8) -> Bytes {
+        Bytes::from(vec![n; 32])
+    }
+
+    #[tokio::test]
+    async fn join_and_leave() {
+        let lobbies = Lobbies::new(8);
+
+        assert!(lobbies.join("a", &key(1)).await.unwrap().is_empty());
+        assert_eq!(lobbies.join("a", &key(2)).await.unwrap(), [key(1)]);
+        // Joining again changes nothing.
+        assert_eq!(lobbies.join("a", &key(2)).await.unwrap(), [key(1)]);
+        assert_eq!(lobbies.members("a").await.unwrap(), [key(1), key(2)]);
+
+        assert_eq!(lobbies.leave("a", &key(1)).await.unwrap(), [key(2)]);
+        assert!(lobbies.leave("a", &key(1)).await.is_none());
+        assert!(lobbies.leave("b", &key(2)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn empty_lobby_is_removed() {
+        let lobbies = Lobbies::new(8);
+        lobbies.join("a", &key(1)).await.unwrap();
+        lobbies.join("b", &key(1)).await.unwrap();
+        lobbies.join("b", &key(2)).await.unwrap();
+
+        assert!(lobbies.leave("a", &key(1)).await.unwrap().is_empty());
+        assert_eq!(lobbies.names().await, ["b"]);
+        assert!(lobbies.members("a").await.is_none());
+
+        assert_eq!(
+            lobbies.leave_all(&key(2)).await,
+            [("b".to_string(), vec![key(1)])]
+        );
+        lobbies.leave_all(&key(1)).await;
+        assert!(lobbies.names().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn full_lobby_refuses() {
+        let lobbies = Lobbies::new(2);
+        lobbies.join("a", &key(1)).await.unwrap();
+        lobbies.join("a", &key(2)).await.unwrap();
+
+        assert!(matches!(
+            lobbies.join("a", &key(3)).await,
+            Err(LobbyError::Full { max: 2 })
+        ));
+        // Members already in can still rejoin.
+        assert!(lobbies.join("a", &key(2)).await.is_ok());
+        assert_eq!(lobbies.members("a").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn invalid_names() {
+        let lobbies = Lobbies::new(8);
+        let long = "x".repeat(MAX_NAME_LEN + 1);
+
+        for name in ["", "  ", long.as_str()] {
+            assert!(matches!(
+                lobbies.join(name, &key(1)).await,
+                Err(LobbyError::InvalidName)
+            ));
+        }
+        assert!(lobbies.join(&long[1..], &key(1)).await.is_ok());
+    }
+}
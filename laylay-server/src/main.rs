@@ -1,14 +1,29 @@
-use std::path::PathBuf;
+use std::{io, path::PathBuf, time::Duration};
 
+use chat::ChatConfig;
 use clap::Parser;
 use client::Client;
+use database::Database;
 use errors::ServerErrors;
-use server::ServerContext;
-use tokio::net::TcpListener;
+use laylay_common::{
+    fingerprint, import_key, Capabilities, CompressionConfig, FileKeyStore, HeartbeatConfig,
+    KeyStore, Policy, RekeyConfig, Version, WebSocket, DEFAULT_MAX_FRAME_SIZE,
+    MIN_PROTOCOL_VERSION,
+};
+use server::{Config, DuplicateLogin, Presence, ServerContext};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::broadcast::error::RecvError,
+    time::timeout,
+};
 
+mod chat;
 mod client;
 mod database;
+mod datagrams;
 mod errors;
+mod lobbies;
+mod migrations;
 mod server;
 
 #[derive(Parser)]
@@ -16,8 +31,80 @@ mod server;
 struct Args {
     #[arg(long, default_value = "0.0.0.0:33033")]
     listen: String,
+    /// Also accepts clients over WebSocket, for browsers, on this address.
+    #[arg(long)]
+    listen_ws: Option<String>,
     #[arg(long, default_value = "info")]
     log: String,
+    /// Largest frame in bytes a client may send before it is disconnected.
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+    /// Oldest wire protocol a client may speak.
+    #[arg(long, default_value_t = MIN_PROTOCOL_VERSION)]
+    min_protocol: u32,
+    /// Oldest client version, as major.minor.patch, that is allowed to connect.
+    #[arg(long)]
+    min_version: Option<Version>,
+    /// Seconds between pings to a client.
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval: u64,
+    /// Seconds a client may stay silent before it is disconnected.
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: u64,
+    /// Seconds the session of a dropped client can still be resumed.
+    #[arg(long, default_value_t = 30)]
+    resume_grace: u64,
+    /// Bytes sent under one key before switching to the next.
+    #[arg(long, default_value_t = RekeyConfig::default().max_bytes)]
+    rekey_bytes: u64,
+    /// Messages sent under one key before switching to the next.
+    #[arg(long, default_value_t = RekeyConfig::default().max_messages)]
+    rekey_messages: u64,
+    /// Seconds one key is used before switching to the next.
+    #[arg(long, default_value_t = RekeyConfig::default().max_age.as_secs())]
+    rekey_interval: u64,
+    /// Messages from this size on, in bytes, are compressed.
+    #[arg(long, default_value_t = CompressionConfig::default().threshold)]
+    compress_threshold: usize,
+    /// zstd level used for compression, 1 is fastest, 22 is smallest.
+    #[arg(long, default_value_t = CompressionConfig::default().level)]
+    compress_level: i32,
+    /// Keeps realtime state on TCP instead of also listening for UDP datagrams.
+    #[arg(long)]
+    no_datagrams: bool,
+    /// Most members a lobby can have.
+    #[arg(long, default_value_t = 16)]
+    max_lobby_size: usize,
+    /// What to do when a key logs in while it is still connected.
+    #[arg(long, value_enum, default_value_t = DuplicateLogin::KickOld)]
+    duplicate_login: DuplicateLogin,
+    /// Chat messages replayed to someone joining a lobby.
+    #[arg(long, default_value_t = 50)]
+    chat_history: usize,
+    /// Chat messages a user may send at once before being slowed down.
+    #[arg(long, default_value_t = 5)]
+    chat_burst: u32,
+    /// Chat messages a user may send per minute in the long run.
+    #[arg(long, default_value_t = 30)]
+    chat_per_minute: u32,
+    /// Seals the private key at rest, a key stored without one is sealed on the next start.
+    #[arg(long, env = "LAYLAY_KEY_PASSPHRASE", hide_env_values = true)]
+    key_passphrase: Option<String>,
+    /// Replaces the server key with one exported earlier and exits.
+    #[arg(long)]
+    import_key: Option<PathBuf>,
+    /// Writes the server key, sealed with the passphrase if there is one, and exits.
+    #[arg(long)]
+    export_key: Option<PathBuf>,
+    /// Prints the fingerprint of the server key, for clients to compare against, and exits.
+    #[arg(long)]
+    fingerprint: bool,
+    /// Prints the fingerprint and key of every known user and exits.
+    #[arg(long)]
+    list_users: bool,
+    /// Brings the database schema up to date and exits.
+    #[arg(long)]
+    migrate_only: bool,
 }
 
 #[tokio::main]
@@ -35,8 +122,142 @@ async fn main() {
             std::fs::create_dir_all(&data)?;
         }
 
-        let ctx = ServerContext::new(data.clone())?;
+        let mut capabilities = Capabilities::supported();
+        if args.no_datagrams {
+            capabilities.remove(Capabilities::DATAGRAM);
+        }
+
+        let config = Config {
+            max_frame_size: args.max_frame_size,
+            policy: Policy {
+                min_protocol: args.min_protocol,
+                min_version: args.min_version.clone(),
+                capabilities,
+            },
+            heartbeat: HeartbeatConfig {
+                interval: Duration::from_secs(args.ping_interval),
+                idle_timeout: Duration::from_secs(args.idle_timeout),
+            },
+            resume_grace: Duration::from_secs(args.resume_grace),
+            rekey: RekeyConfig {
+                max_bytes: args.rekey_bytes,
+                max_messages: args.rekey_messages,
+                max_age: Duration::from_secs(args.rekey_interval),
+            },
+            compression: CompressionConfig {
+                threshold: args.compress_threshold,
+                level: args.compress_level,
+            },
+            max_lobby_size: args.max_lobby_size,
+            duplicate_login: args.duplicate_login,
+            chat: ChatConfig {
+                history: args.chat_history,
+                burst: args.chat_burst,
+                per_minute: args.chat_per_minute,
+            },
+        };
+        let keys = FileKeyStore::new(data.join("prikey.bin"), args.key_passphrase.clone());
+
+        if let Some(path) = &args.import_key {
+            // Only read, the source may well be the sole copy of the key.
+            let key = import_key(&std::fs::read(path)?, args.key_passphrase.as_deref())?;
+            keys.store(&key)?;
+            tracing::info!("imported key from {}", path.display());
+            return Ok(());
+        }
+
+        if let Some(path) = &args.export_key {
+            let key = keys.load_or_create()?;
+            FileKeyStore::new(path.clone(), args.key_passphrase.clone()).store(&key)?;
+            tracing::info!("exported key to {}", path.display());
+            return Ok(());
+        }
+
+        if args.fingerprint {
+            let key = keys.load_or_create()?;
+            println!("{}", fingerprint(&key.public_key().to_sec1_bytes()));
+            return Ok(());
+        }
+
+        if args.migrate_only {
+            Database::new(data.clone())?;
+            return Ok(());
+        }
+
+        if args.list_users {
+            for pubkey in Database::read_only(data.clone())?.users().await? {
+                println!("{}  {}", fingerprint(&pubkey), hex::encode(&pubkey));
+            }
+            return Ok(());
+        }
+
         let server = TcpListener::bind(&args.listen).await?;
+        let udp = if args.no_datagrams {
+            None
+        } else {
+            Some(UdpSocket::bind(&args.listen).await?)
+        };
+
+        let ctx = ServerContext::new(data.clone(), &keys, config, udp)?;
+        tracing::info!(
+            "server key {}",
+            fingerprint(&ctx.prikey.public_key().to_sec1_bytes())
+        );
+
+        let mut presence = ctx.subscribe_presence();
+        tokio::spawn(async move {
+            loop {
+                match presence.recv().await {
+                    Ok(Presence::Online(pubkey)) => {
+                        tracing::info!("{} online", fingerprint(&pubkey))
+                    }
+                    Ok(Presence::Offline(pubkey)) => {
+                        tracing::info!("{} offline", fingerprint(&pubkey))
+                    }
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        if ctx.datagrams.is_some() {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                if let Some(datagrams) = &ctx.datagrams {
+                    if let Err(e) = datagrams.run().await {
+                        tracing::error!("datagrams: {e}");
+                    }
+                }
+            });
+        }
+
+        if let Some(listen_ws) = &args.listen_ws {
+            let server = TcpListener::bind(listen_ws).await?;
+            let ctx = ctx.clone();
+
+            tokio::spawn(async move {
+                while let Ok((stream, _addr)) = server.accept().await {
+                    let ctx = ctx.clone();
+                    tokio::spawn(async move {
+                        let max_frame_size = ctx.config.max_frame_size;
+                        let upgrade = WebSocket::accept(stream, max_frame_size);
+                        let ret = match timeout(ctx.config.heartbeat.idle_timeout, upgrade).await {
+                            Ok(Ok(ws)) => Client::new(ctx, ws).await.map(|_| ()),
+                            Ok(Err(e)) => Err(e.into()),
+                            Err(_) => Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "no websocket upgrade",
+                            )
+                            .into()),
+                        };
+
+                        if let Err(e) = ret {
+                            tracing::error!("{e}");
+                        }
+                    });
+                }
+            });
+        }
 
         while let Ok((stream, _addr)) = server.accept().await {
             let ctx = ctx.clone();
@@ -0,0 +1,178 @@
+use std::error::Error;
+
+use rusqlite::{Connection, OptionalExtension};
+
+/// One schema change.
+enum Migration {
+    Sql(&'static str),
+    /// Adds a column unless it is already there, databases from before the
+    /// migrations may have been created with it.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        decl: &'static str,
+    },
+}
+
+/// Schema changes in order, `PRAGMA user_version` counts how many of them ran.
+/// Only ever append, a released migration must not change. Everything after the
+/// initial schema has to work on databases that already have it.
+const MIGRATIONS: &[Migration] = &[
+    Migration::Sql(include_str!("migrations/001_initial.sql")),
+    Migration::AddColumn {
+        table: "user_session",
+        column: "rtt_ms",
+        decl: "REAL",
+    },
+    Migration::AddColumn {
+        table: "logs",
+        column: "logged",
+        decl: "DATETIME",
+    },
+    Migration::Sql(include_str!("migrations/004_chat.sql")),
+];
+
+/// Schema version this binary brings a database to.
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Runs the pending migrations in one transaction, so the database is either
+/// fully upgraded or left untouched. Refuses databases newer than this binary.
+pub fn migrate(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+    let tx = conn.transaction()?;
+    let version = check(&tx)?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tracing::info!("migrating database to schema version {}", i + 1);
+
+        match migration {
+            Migration::Sql(sql) => tx.execute_batch(sql)?,
+            Migration::AddColumn {
+                table,
+                column,
+                decl,
+            } => {
+                if !has_column(&tx, table, column)? {
+                    tx.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))?;
+                }
+            }
+        }
+    }
+
+    tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// The schema version of the database, refuses one newer than this binary.
+pub fn check(conn: &Connection) -> Result<usize, Box<dyn Error>> {
+    let mut version: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+    if version == 0 {
+        version = legacy_version(conn)?;
+    }
+
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "database schema version {version} is newer than this server ({SCHEMA_VERSION})"
+        )
+        .into());
+    }
+
+    Ok(version)
+}
+
+fn has_table(conn: &Connection, name: &str) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?",
+        [name],
+        |_| Ok(()),
+    )
+    .optional()
+    .map(|r| r.is_some())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT 1 FROM pragma_table_info(?) WHERE name = ?",
+        [table, column],
+        |_| Ok(()),
+    )
+    .optional()
+    .map(|r| r.is_some())
+}
+
+/// Databases from before the migrations have no version but the initial schema,
+/// the later changes check themselves whether they are already there.
+fn legacy_version(conn: &Connection) -> Result<usize, rusqlite::Error> {
+    Ok(if has_table(conn, "user")? { 1 } else { 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |r| r.get(0))
+            .unwrap()
+    }
+
+    fn assert_current(conn: &Connection) {
+        assert_eq!(version(conn), SCHEMA_VERSION);
+        assert!(has_column(conn, "user_session", "rtt_ms").unwrap());
+        assert!(has_column(conn, "logs", "logged").unwrap());
+        assert!(has_table(conn, "chat").unwrap());
+    }
+
+    /// A database with the schema from before the migrations and no version.
+    fn baseline() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("migrations/001_initial.sql"))
+            .unwrap();
+        conn
+    }
+
+    #[test]
+    fn fresh_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_current(&conn);
+        assert!(has_table(&conn, "user").unwrap());
+
+        // running again is a no-op
+        migrate(&mut conn).unwrap();
+        assert_current(&conn);
+    }
+
+    #[test]
+    fn baseline_database() {
+        let mut conn = baseline();
+        assert_eq!(version(&conn), 0);
+
+        migrate(&mut conn).unwrap();
+        assert_current(&conn);
+    }
+
+    #[test]
+    fn columns_already_there() {
+        let mut conn = baseline();
+        conn.execute_batch(
+            "ALTER TABLE user_session ADD COLUMN rtt_ms REAL;
+             ALTER TABLE logs ADD COLUMN logged DATETIME;",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_current(&conn);
+    }
+
+    #[test]
+    fn newer_database_refused() {
+        let mut conn = baseline();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+
+        assert!(migrate(&mut conn).is_err());
+        assert_eq!(version(&conn), SCHEMA_VERSION + 1);
+        assert!(!has_table(&conn, "chat").unwrap());
+    }
+}
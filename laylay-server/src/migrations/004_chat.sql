@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS chat (
+    id INTEGER PRIMARY KEY,
+    session_id INTEGER,
+    lobby VARCHAR,
+    message VARCHAR,
+    sent DATETIME
+);
+CREATE INDEX IF NOT EXISTS chat_lobby ON chat(lobby, id);
@@ -1,35 +1,212 @@
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 
-use laylay_common::{get_private_key, Bytes, Info, Message, SecretKey, Version};
-use tokio::sync::RwLock;
+use laylay_common::{
+    Bytes, CompressionConfig, HeartbeatConfig, Info, KeyStore, Message, Policy, RekeyConfig,
+    SecretKey,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, Mutex, RwLock},
+};
 
-use crate::{client::Client, database::Database, errors::ServerErrors};
+use crate::{
+    chat::{ChatConfig, ChatLimits},
+    client::Client,
+    database::Database,
+    datagrams::Datagrams,
+    errors::ServerErrors,
+    lobbies::Lobbies,
+};
+
+pub struct Config {
+    pub max_frame_size: usize,
+    /// Minimum requirements and capabilities offered to clients.
+    pub policy: Policy,
+    /// Ping interval and idle timeout for clients speaking [`laylay_common::Capabilities::HEARTBEAT`].
+    pub heartbeat: HeartbeatConfig,
+    /// How long the session of a dropped client waits to be resumed.
+    pub resume_grace: Duration,
+    /// When the keys of a client connection are replaced.
+    pub rekey: RekeyConfig,
+    /// Applied to messages sent to clients speaking [`laylay_common::Capabilities::COMPRESSION`].
+    pub compression: CompressionConfig,
+    /// Most members a lobby can have.
+    pub max_lobby_size: usize,
+    pub duplicate_login: DuplicateLogin,
+    pub chat: ChatConfig,
+}
+
+/// What happens when a key logs in while a connection with it is still open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicateLogin {
+    /// The old connection is closed, most likely it was left behind by a network change.
+    KickOld,
+    /// The new connection is refused until the old one is gone.
+    RejectNew,
+}
+
+/// A key came online or went offline, see [`ServerContext::subscribe_presence`].
+#[derive(Debug, Clone)]
+pub enum Presence {
+    Online(Bytes),
+    Offline(Bytes),
+}
+
+/// A session whose connection dropped, kept until it is resumed or the grace period ends.
+struct Parked {
+    pubkey: Bytes,
+    session_id: i64,
+}
 
 pub struct ServerContext {
     pub prikey: SecretKey,
     pub db: Database,
-    pub greeting: Message,
+    pub info: Info,
+    pub config: Config,
+    /// The current connection of every online key.
     pub clients: RwLock<HashMap<Bytes, Arc<Client>>>,
+    presence: broadcast::Sender<Presence>,
+    /// `None` when realtime datagrams are turned off.
+    pub datagrams: Option<Datagrams>,
+    pub lobbies: Lobbies,
+    pub chat_limits: ChatLimits,
+    parked: Mutex<HashMap<Bytes, Parked>>,
 }
 
 impl ServerContext {
-    pub fn new(folder: PathBuf) -> Result<Arc<Self>, ServerErrors> {
-        let prikey = get_private_key(folder.clone())?;
-        let greeting = Message::Greeting {
-            pubkey: prikey.public_key().to_sec1_bytes().into(),
-            version: Version::get(),
-            info: Info::new()?,
-        };
+    pub fn new(
+        folder: PathBuf,
+        keys: &dyn KeyStore,
+        config: Config,
+        udp: Option<UdpSocket>,
+    ) -> Result<Arc<Self>, ServerErrors> {
+        let prikey = keys.load_or_create()?;
 
         Ok(Arc::new(Self {
             prikey,
             db: Database::new(folder)?,
-            greeting,
+            info: Info::new()?,
             clients: RwLock::new(HashMap::new()),
+            presence: broadcast::channel(64).0,
+            datagrams: udp.map(Datagrams::new),
+            lobbies: Lobbies::new(config.max_lobby_size),
+            chat_limits: ChatLimits::new(config.chat),
+            parked: Mutex::new(HashMap::new()),
+            config,
         }))
     }
 
-    pub async fn add_client(&self, pubkey: Bytes, cl: Arc<Client>) {
-        self.clients.write().await.insert(pubkey, cl);
+    /// Marks the key online with this connection, an older one of the same key is kicked.
+    /// Under [`DuplicateLogin::RejectNew`] the older one stays and `false` is returned,
+    /// unless this connection resumed its session.
+    pub async fn add_client(&self, pubkey: Bytes, cl: Arc<Client>) -> bool {
+        let old = {
+            let mut clients = self.clients.write().await;
+
+            if self.config.duplicate_login == DuplicateLogin::RejectNew
+                && clients
+                    .get(&pubkey)
+                    .is_some_and(|old| old.session_id() != cl.session_id())
+            {
+                return false;
+            }
+
+            clients.insert(pubkey.clone(), cl)
+        };
+
+        match old {
+            Some(old) => old.kick("logged in from another connection").await,
+            None => {
+                // Nobody listening is fine.
+                let _ = self.presence.send(Presence::Online(pubkey));
+            }
+        }
+
+        true
+    }
+
+    /// Marks the key offline unless a newer connection took over, returns whether it did.
+    pub async fn remove_client(&self, pubkey: &Bytes, cl: &Arc<Client>) -> bool {
+        {
+            let mut clients = self.clients.write().await;
+
+            match clients.get(pubkey) {
+                Some(current) if Arc::ptr_eq(current, cl) => clients.remove(pubkey),
+                _ => return false,
+            };
+        }
+
+        let _ = self.presence.send(Presence::Offline(pubkey.clone()));
+
+        true
+    }
+
+    /// Whether the key is online with a connection that `token` does not resume.
+    pub async fn is_duplicate(&self, pubkey: &Bytes, token: Option<&Bytes>) -> bool {
+        match self.clients.read().await.get(pubkey) {
+            Some(current) => !token.is_some_and(|token| current.issued(token)),
+            None => false,
+        }
+    }
+
+    /// Every key coming online or going offline from now on.
+    pub fn subscribe_presence(&self) -> broadcast::Receiver<Presence> {
+        self.presence.subscribe()
+    }
+
+    /// Sends a message made by `msg` to each of `pubkeys` that is connected. Never waits
+    /// for a member, one that does not keep up is disconnected instead, as it would go
+    /// on with a wrong picture of its lobbies and has to join them again after reconnecting.
+    pub async fn broadcast(&self, pubkeys: &[Bytes], msg: impl Fn() -> Message) {
+        let clients: Vec<Arc<Client>> = {
+            let clients = self.clients.read().await;
+            pubkeys
+                .iter()
+                .filter_map(|pubkey| clients.get(pubkey).cloned())
+                .collect()
+        };
+
+        for client in clients {
+            if let Err(e) = client.try_send(msg()) {
+                tracing::warn!("dropped broadcast: {e}");
+                client.close();
+            }
+        }
+    }
+
+    /// Keeps the session resumable under `token`, it is ended once the grace period is over.
+    pub async fn park(self: Arc<Self>, token: Bytes, pubkey: Bytes, session_id: i64) {
+        self.parked
+            .lock()
+            .await
+            .insert(token.clone(), Parked { pubkey, session_id });
+
+        tokio::spawn(async move {
+            tokio::time::sleep(self.config.resume_grace).await;
+
+            if self.parked.lock().await.remove(&token).is_some() {
+                if let Err(e) = self.db.end_session(session_id).await {
+                    tracing::error!("{e}");
+                }
+            }
+        });
+    }
+
+    /// Takes back a session, the token is only valid for the key it was issued to. A
+    /// connection that still holds the session, as its drop went unnoticed so far, hands
+    /// it over and is kicked by [`ServerContext::add_client`].
+    pub async fn resume(&self, token: &Bytes, pubkey: &Bytes) -> Option<i64> {
+        if let Some(current) = self.clients.read().await.get(pubkey) {
+            if let Some(session_id) = current.hand_over(token) {
+                return Some(session_id);
+            }
+        }
+
+        let mut parked = self.parked.lock().await;
+
+        match parked.get(token) {
+            Some(p) if p.pubkey == *pubkey => parked.remove(token).map(|p| p.session_id),
+            _ => None,
+        }
     }
 }